@@ -1,4 +1,7 @@
+use crate::utils::requests::TrustedProxies;
+use ipnet::IpNet;
 use serde::Deserialize;
+use std::net::IpAddr;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AuthConfig {
@@ -16,6 +19,12 @@ pub struct FileConfig {
     pub height: u32,
     pub delay: u32,
     pub file_path: String,
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64, // in seconds
+}
+
+fn default_flush_interval() -> u64 {
+    5
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -24,12 +33,145 @@ pub struct StateConfig {
     pub address: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct LimitsConfig {
+    /// Pixels one IP address may place per cooldown, across all of its sessions
+    #[serde(default = "default_ip_burst")]
+    pub ip_burst: u32,
+    /// Pixels one network prefix may place per cooldown, across all of its addresses
+    #[serde(default = "default_prefix_burst")]
+    pub prefix_burst: u32,
+    /// Prefix lengths addresses are aggregated into, for `prefix_burst` and IP bans
+    #[serde(default = "default_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    #[serde(default = "default_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+    /// Placement credits an idle user can save up, one being refilled every `delay`.
+    /// 1 keeps the strict one pixel per `delay` rule.
+    #[serde(default = "default_bucket_size")]
+    pub bucket_size: u32,
+    /// Leading zero bits of the proof of work required with each placement, 0 disables it
+    #[serde(default)]
+    pub pow_difficulty: u8,
+    /// Actors and IPs tracked at most (each), newcomers are refused beyond it
+    #[serde(default = "default_max_tracked")]
+    pub max_tracked: usize,
+    #[serde(default = "default_sweep_interval")]
+    pub sweep_interval: u64, // in seconds
+}
+
+fn default_ip_burst() -> u32 {
+    30
+}
+
+fn default_prefix_burst() -> u32 {
+    60
+}
+
+fn default_ipv4_prefix() -> u8 {
+    32
+}
+
+fn default_ipv6_prefix() -> u8 {
+    64
+}
+
+fn default_bucket_size() -> u32 {
+    1
+}
+
+fn default_max_tracked() -> usize {
+    1_000_000
+}
+
+fn default_sweep_interval() -> u64 {
+    60
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            ip_burst: default_ip_burst(),
+            prefix_burst: default_prefix_burst(),
+            ipv4_prefix: default_ipv4_prefix(),
+            ipv6_prefix: default_ipv6_prefix(),
+            bucket_size: default_bucket_size(),
+            pow_difficulty: 0,
+            max_tracked: default_max_tracked(),
+            sweep_interval: default_sweep_interval(),
+        }
+    }
+}
+
+/// OpenID Connect provider players log in with
+#[derive(Debug, Deserialize, Clone)]
+pub struct OidcConfig {
+    pub issuer: String, // discovery happens at `<issuer>/.well-known/openid-configuration`
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_url: String, // public URL of `/api/login/callback`
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub require_login: bool, // only logged in users may place pixels
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "profile".to_string()]
+}
+
+/// Forwarding header set by the trusted proxies
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor, // Traefik, nginx, HAProxy...
+    Forwarded, // RFC 7239
+    XRealIp,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ProxyConfig {
+    /// Proxies allowed to set forwarding headers, as CIDRs or plain addresses
+    #[serde(default)]
+    pub trusted: Vec<String>,
+    /// The only header read, others may come straight from the client
+    #[serde(default)]
+    pub header: ForwardedHeader,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct AppConfig {
     pub auth: AuthConfig,
     pub cookies: CookieConfig,
     pub file: FileConfig,
     pub state: StateConfig,
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    pub oidc: Option<OidcConfig>,
+}
+
+impl ProxyConfig {
+    pub fn trusted_proxies(&self) -> TrustedProxies {
+        TrustedProxies {
+            networks: self.trusted_networks(),
+            header: self.header,
+        }
+    }
+
+    fn trusted_networks(&self) -> Vec<IpNet> {
+        self.trusted
+            .iter()
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .unwrap_or_else(|_| panic!("Invalid trusted proxy: {}", entry))
+            })
+            .collect()
+    }
 }
 
 impl AppConfig {
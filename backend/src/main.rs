@@ -3,18 +3,31 @@ mod routes;
 mod utils;
 
 use crate::config::AppConfig;
-use crate::routes::{admin::*, canva::*, pixel::*, state::*};
+use crate::routes::{
+    admin::*, auth::*, bans::*, canva::*, image::*, live::*, moderation::*, pixel::*, state::*,
+};
+use crate::utils::admin_sessions::AdminSessions;
+use crate::utils::bans::{BanList, bans_path};
+use crate::utils::canvas::{CanvasBuffer, flush_canvas, spawn_flusher};
+use crate::utils::events::EventLog;
+use crate::utils::history::{PlacementLog, history_path};
+use crate::utils::limiter::{Limiter, spawn_sweeper};
+use crate::utils::login_guard::LoginGuard;
+use crate::utils::oidc::OidcClient;
+use crate::utils::pow::{MAX_DIFFICULTY, ProofOfWork};
+use crate::utils::session::ensure_session;
+use crate::utils::settings::{RuntimeSettings, settings_path};
+use crate::utils::timelapse;
 use axum::extract::FromRef;
 use axum::{
-    Router,
+    Router, middleware,
     routing::{get, post},
 };
 use axum_extra::extract::cookie::Key;
 use base64::engine::{Engine, general_purpose};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::sync::Mutex;
+use std::{future::IntoFuture, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::{Mutex, broadcast};
 use tower_http::services::ServeDir;
-use tracing_subscriber;
 
 impl FromRef<AppState> for Key {
     fn from_ref(app_state: &AppState) -> Key {
@@ -26,7 +39,21 @@ impl FromRef<AppState> for Key {
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let config = AppConfig::from_file();
+    let mut config = AppConfig::from_file();
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("timelapse") {
+        if let Err(e) = timelapse::run_command(&config, &args[2..]) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Admin changes made while running take precedence over config.toml
+    let settings = RuntimeSettings::load(&settings_path(&config.file.file_path))
+        .expect("Failed to load runtime settings");
+    settings.apply(&mut config);
 
     let key_bytes = general_purpose::STANDARD
         .decode(&config.cookies.key_base64)
@@ -34,56 +61,167 @@ async fn main() {
 
     let key = Key::from(&key_bytes); // use slice &[u8] to build cookie Key
 
-    let canvas_size = Arc::new(Mutex::new(CanvasSize {
+    let config_size = CanvasSize {
         width: config.file.width,
         height: config.file.height,
-    }));
+    };
+
+    // Initialize the pixel file if it doesn't exist
+    // Uncomment the following lines if you want to initialize the pixel file
+    // init_pixel_file("state/pixels.bin", &config_size).expect("Failed to init pixels.bin");
+
+    // The file header is authoritative, width/height from the config only describe headerless files
+    let canvas = CanvasBuffer::recover(&config.file.file_path, &config_size)
+        .expect("Failed to recover pixels.bin");
+    let size = canvas.size();
+    if size.width != config_size.width || size.height != config_size.height {
+        tracing::info!(
+            "Using the {}x{} canvas size stored in {} instead of the configured {}x{}",
+            size.width,
+            size.height,
+            config.file.file_path,
+            config_size.width,
+            config_size.height
+        );
+    }
+    let canvas_size = Arc::new(Mutex::new(size));
+
+    let history = PlacementLog::open(&history_path(&config.file.file_path))
+        .expect("Failed to open the placement log");
+
+    let bans = BanList::load(&bans_path(&config.file.file_path)).expect("Failed to load the bans");
+
+    let (events, _) = broadcast::channel(1024);
 
     let shared_state = AppState {
         canvas_size: Arc::clone(&canvas_size),
-        file_lock: Arc::new(Mutex::new(())),
+        canvas: Arc::new(Mutex::new(canvas)),
         delay: Arc::new(Mutex::new(config.file.delay)),
-        ip_timestamps: Arc::new(Mutex::new(HashMap::new())),
-        cookie_key: key,
+        limiter: Arc::new(Limiter::new(&config.limits)),
+        bucket_size: Arc::new(Mutex::new(config.limits.bucket_size)),
+        pow: Arc::new(ProofOfWork::new(key.signing())),
+        pow_difficulty: Arc::new(Mutex::new(config.limits.pow_difficulty.min(MAX_DIFFICULTY))),
+        cookie_key: key.clone(),
         auth: config.auth,
         file_path: Arc::new(config.file.file_path.clone()),
         active: Arc::new(Mutex::new(config.state.active)),
+        settings: Arc::new(Mutex::new(settings)),
+        history: Arc::new(Mutex::new(history)),
+        events,
+        event_log: Arc::new(Mutex::new(EventLog::new())),
+        trusted_proxies: Arc::new(config.proxy.trusted_proxies()),
+        oidc: config
+            .oidc
+            .clone()
+            .map(|oidc| Arc::new(OidcClient::new(oidc))),
+        bans: Arc::new(Mutex::new(bans)),
+        login_guard: Arc::new(LoginGuard::default()),
+        admin_sessions: Arc::new(AdminSessions::default()),
     };
 
-    // Initialize the pixel file if it doesn't exist
-    // Uncomment the following lines if you want to initialize the pixel file
-    // {
-    //     let canvas_size_guard = canvas_size.lock().await;
-    //     init_pixel_file("state/pixels.bin", &*canvas_size_guard)
-    //         .expect("Failed to init pixels.bin");
-    // }
+    spawn_flusher(
+        shared_state.clone(),
+        Duration::from_secs(config.file.flush_interval),
+    );
+    spawn_sweeper(
+        shared_state.clone(),
+        Duration::from_secs(config.limits.sweep_interval),
+    );
 
     let app = Router::new()
         .route("/api/size", get(get_canvas_size))
         .route("/api/pixel", post(handle_pixel_request))
+        .route("/api/pixel/{x}/{y}", get(get_pixel_info))
         .route("/api/pixels", get(get_all_pixels))
         .route("/api/pixels", post(get_pixel_region))
+        .route("/api/pixels.bin", get(get_pixels_binary))
+        .route("/api/pixels/since/{version}", get(get_pixels_since))
+        .route("/api/canvas.png", get(get_canvas_png))
         .route("/api/delay", get(get_delay))
         .route("/api/active", get(get_active))
+        .route("/api/credits", get(get_credits))
+        .route("/api/cooldown", get(get_cooldown))
+        .route("/api/challenge", get(get_challenge))
         .route("/api/admin-login", post(admin_login))
+        .route("/api/admin/logout", post(admin_logout))
+        .route(
+            "/api/admin/sessions",
+            get(admin_sessions).delete(admin_revoke_sessions),
+        )
         .route("/api/admin/pixels", post(admin_whitening))
         .route("/api/admin/size", post(update_canvas_size))
         .route("/api/admin/active", post(update_admin_active))
         .route("/api/admin/reset", post(admin_reset))
         .route("/api/admin/delay", post(admin_update_delay))
+        .route("/api/admin/bucket-size", post(admin_update_bucket_size))
+        .route(
+            "/api/admin/pow-difficulty",
+            post(admin_update_pow_difficulty),
+        )
+        .route("/api/admin/limiter", get(admin_limiter_metrics))
+        .route("/api/admin/bans", get(list_bans).post(create_ban))
+        .route(
+            "/api/admin/bans/{id}",
+            get(get_ban).put(update_ban).delete(delete_ban),
+        )
+        .route("/api/admin/timelapse.gif", get(admin_timelapse))
+        .route("/api/admin/pixel/{x}/{y}/history", get(admin_pixel_history))
+        .route("/api/admin/rollback", post(admin_rollback))
+        .route("/api/admin/revert-actor", post(admin_revert_actor))
         .route("/api/me", get(me))
+        .route("/api/login", get(login))
+        .route("/api/login/callback", get(login_callback))
+        .route("/api/logout", post(logout))
+        .route("/api/ws", get(ws_handler))
+        .route("/api/events", get(sse_handler))
         .fallback_service(ServeDir::new("static/").not_found_service(get(spa_fallback)))
-        .with_state(shared_state);
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            ensure_session,
+        ))
+        .with_state(shared_state.clone());
 
     let listener = tokio::net::TcpListener::bind(&config.state.address)
         .await
         .expect("Failed to bind address");
 
     tracing::info!("Listening on http://{}", &config.state.address);
-    axum::serve(
+    let server = axum::serve(
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .expect("Server error");
+    );
+
+    // Live streams never end on their own, so don't wait for connections to drain
+    tokio::select! {
+        result = server.into_future() => result.expect("Server error"),
+        _ = shutdown_signal() => tracing::info!("Shutting down"),
+    }
+
+    // Persist whatever the flusher didn't get to yet
+    flush_canvas(&shared_state).await;
+    tracing::info!("Canvas flushed, bye");
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
@@ -1,18 +1,31 @@
 use crate::routes::{
-    pixel::{init_pixel_file, resize_canvas_locked},
-    state::{AppState, CanvasSize},
+    pixel::{init_pixel_file, resize_canvas_locked, validate_canvas_size},
+    state::{AppState, CanvasEvent, CanvasSize},
+};
+use crate::utils::{
+    admin_sessions::{ADMIN_COOKIE, ADMIN_SESSION_TTL, AdminSession},
+    canvas::CanvasBuffer,
+    events::publish,
+    history::{history_path, now_millis},
+    limiter::too_many_requests,
+    pow::MAX_DIFFICULTY,
+    requests::get_ip,
+    session::current_user,
+    settings::update_settings,
 };
 use axum::{
     Json,
-    extract::{Form, State},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{ConnectInfo, Form, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
-use axum_extra::extract::cookie::{Cookie, PrivateCookieJar};
+use axum_extra::extract::cookie::{Cookie, PrivateCookieJar, SameSite};
 use bcrypt::verify;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{Value, json};
 use std::fs;
+use std::net::SocketAddr;
+use std::time::SystemTime;
 
 #[derive(Deserialize)]
 pub struct LoginForm {
@@ -22,19 +35,41 @@ pub struct LoginForm {
 #[derive(Serialize)]
 struct UserInfo {
     admin: bool,
+    id: Option<String>,   // set when logged in through OpenID Connect
+    name: Option<String>, // display name from the provider
 }
 
 pub async fn admin_login(
     State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     jar: PrivateCookieJar,
     Form(form): Form<LoginForm>,
-) -> impl IntoResponse {
+) -> Response {
+    let ip = get_ip(&headers, peer, &state.trusted_proxies);
+    let prefix = state.limiter.prefix_of(ip);
+    let attempt = match state
+        .login_guard
+        .begin_attempt(prefix, SystemTime::now())
+        .await
+    {
+        Ok(attempt) => attempt,
+        // Answered without running bcrypt
+        Err(wait) => {
+            return too_many_requests(wait, "Too many failed attempts, please try again later");
+        }
+    };
+
     let hashed = &state.auth.admin_hashed_password;
 
     if verify(&form.password, hashed).unwrap_or(false) {
-        let mut cookie = Cookie::new("admin", "true");
+        state.login_guard.record_success(prefix).await;
+        tracing::info!("Admin logged in from {}", ip);
+        let session = state.admin_sessions.create(ip, now_millis());
+        let mut cookie = Cookie::new(ADMIN_COOKIE, session.id);
         cookie.set_http_only(true);
-        cookie.set_max_age(time::Duration::hours(12));
+        cookie.set_same_site(SameSite::Lax);
+        cookie.set_max_age(time::Duration::milliseconds(ADMIN_SESSION_TTL as i64));
         cookie.set_path("/");
 
         let jar = jar.add(cookie);
@@ -45,7 +80,9 @@ pub async fn admin_login(
                 Json(serde_json::json!({ "message": "Login successful" })),
             ),
         )
+            .into_response()
     } else {
+        tracing::warn!("Failed admin login from {} (attempt {})", ip, attempt);
         (
             jar,
             (
@@ -53,22 +90,88 @@ pub async fn admin_login(
                 Json(serde_json::json!({ "error": "Bad Password" })),
             ),
         )
+            .into_response()
     }
 }
 
-pub async fn me(State(_state): State<AppState>, jar: PrivateCookieJar) -> impl IntoResponse {
-    let is_admin = jar
-        .get("admin")
-        .map(|cookie| cookie.value() == "true")
-        .unwrap_or(false);
+pub async fn me(State(state): State<AppState>, jar: PrivateCookieJar) -> impl IntoResponse {
+    let is_admin = is_user_admin(&state, &jar);
+
+    let user = current_user(&jar);
+    Json(UserInfo {
+        admin: is_admin,
+        id: user.as_ref().map(|u| u.id.clone()),
+        name: user.map(|u| u.name),
+    })
+}
 
-    Json(UserInfo { admin: is_admin })
+/// Whether the cookie holds a live admin session, which is then marked as seen
+pub fn is_user_admin(state: &AppState, jar: &PrivateCookieJar) -> bool {
+    jar.get(ADMIN_COOKIE)
+        .is_some_and(|cookie| state.admin_sessions.touch(cookie.value(), now_millis()))
 }
 
-pub fn is_user_admin(jar: &PrivateCookieJar) -> bool {
-    jar.get("admin")
-        .map(|cookie| cookie.value() == "true")
-        .unwrap_or(false)
+#[derive(Serialize)]
+struct SessionInfo {
+    #[serde(flatten)]
+    session: AdminSession,
+    current: bool, // the session making the request
+}
+
+pub async fn admin_logout(State(state): State<AppState>, jar: PrivateCookieJar) -> Response {
+    if jar
+        .get(ADMIN_COOKIE)
+        .is_some_and(|cookie| state.admin_sessions.revoke(cookie.value()))
+    {
+        tracing::info!("Admin logged out");
+    }
+    (
+        jar.remove(Cookie::build(ADMIN_COOKIE).path("/")),
+        Json(json!({ "message": "Logged out" })),
+    )
+        .into_response()
+}
+
+pub async fn admin_sessions(State(state): State<AppState>, jar: PrivateCookieJar) -> Response {
+    if !is_user_admin(&state, &jar) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Unauthorized" })),
+        )
+            .into_response();
+    }
+    let current = jar.get(ADMIN_COOKIE);
+    let sessions: Vec<SessionInfo> = state
+        .admin_sessions
+        .list(now_millis())
+        .into_iter()
+        .map(|session| SessionInfo {
+            current: current.as_ref().is_some_and(|c| c.value() == session.id),
+            session,
+        })
+        .collect();
+    Json(sessions).into_response()
+}
+
+/// Log every admin out, including the caller
+pub async fn admin_revoke_sessions(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+) -> Response {
+    if !is_user_admin(&state, &jar) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Unauthorized" })),
+        )
+            .into_response();
+    }
+    let revoked = state.admin_sessions.revoke_all();
+    tracing::warn!("Revoked all {} admin sessions", revoked);
+    (
+        jar.remove(Cookie::build(ADMIN_COOKIE).path("/")),
+        Json(json!({ "message": "All admin sessions revoked", "revoked": revoked })),
+    )
+        .into_response()
 }
 
 pub async fn update_canvas_size(
@@ -76,7 +179,7 @@ pub async fn update_canvas_size(
     jar: PrivateCookieJar,
     Json(payload): Json<CanvasSize>,
 ) -> impl IntoResponse {
-    if !is_user_admin(&jar) {
+    if !is_user_admin(&state, &jar) {
         return (
             StatusCode::UNAUTHORIZED,
             Json(json!({ "error": "Unauthorized" })),
@@ -104,7 +207,7 @@ pub async fn update_admin_active(
     jar: PrivateCookieJar,
     Json(payload): Json<serde_json::Value>,
 ) -> impl IntoResponse {
-    if !is_user_admin(&jar) {
+    if !is_user_admin(&state, &jar) {
         return (
             StatusCode::UNAUTHORIZED,
             Json(json!({ "error": "Unauthorized" })),
@@ -119,6 +222,14 @@ pub async fn update_admin_active(
     let mut active_lock = state.active.lock().await;
     *active_lock = active;
 
+    if let Err(e) = update_settings(&state, |s| s.active = Some(active)).await {
+        tracing::error!("Failed to persist active state: {}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("State updated but not persisted: {}", e) })),
+        );
+    }
+
     (
         StatusCode::OK,
         Json(json!({ "message": "State updated successfully" })),
@@ -130,51 +241,95 @@ pub async fn admin_reset(
     jar: PrivateCookieJar,
     Json(canvas_size): Json<CanvasSize>,
 ) -> impl IntoResponse {
-    if !is_user_admin(&jar) {
+    if !is_user_admin(&state, &jar) {
         return (
             StatusCode::UNAUTHORIZED,
             Json(json!({ "error": "Unauthorized" })),
         );
     }
 
+    if let Err(e) = validate_canvas_size(&canvas_size) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": e.to_string() })),
+        );
+    }
+
     // Prepare file paths
     let pixel_file_path = &*state.file_path;
 
-    // Get current date as YYYY-MM-DD without chrono
-    let now = std::time::SystemTime::now();
-    let datetime: time::OffsetDateTime = now.into();
-    let date_str = format!(
-        "{:04}-{:02}-{:02}",
+    // Archives are named after the reset time, down to the millisecond so none is overwritten
+    let datetime: time::OffsetDateTime = std::time::SystemTime::now().into();
+    let suffix = format!(
+        "{:04}-{:02}-{:02}T{:02}-{:02}-{:02}.{:03}",
         datetime.year(),
         datetime.month() as u8,
-        datetime.day()
+        datetime.day(),
+        datetime.hour(),
+        datetime.minute(),
+        datetime.second(),
+        datetime.millisecond()
     );
-    let backup_path = format!("{}-{}", pixel_file_path, date_str);
+    let backup_path = format!("{}-{}", pixel_file_path, suffix);
 
-    // Try to copy the file before resetting
-    if let Err(e) = fs::copy(pixel_file_path, &backup_path) {
+    let mut canvas_size_lock = state.canvas_size.lock().await;
+    let mut canvas = state.canvas.lock().await;
+
+    // Try to copy the file, with pending changes flushed, before resetting
+    if let Err(e) = canvas
+        .snapshot()
+        .and_then(|_| fs::copy(pixel_file_path, &backup_path))
+    {
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({ "error": format!("Failed to backup canvas file: {}", e) })),
         );
     }
 
-    // Reset the canvas file
-    match init_pixel_file(pixel_file_path, &canvas_size) {
-        Ok(_) => {
-            // Reset the canvas size in the state
-            let mut canvas_size_lock = state.canvas_size.lock().await;
-            *canvas_size_lock = canvas_size;
-            (
-                StatusCode::OK,
-                Json(json!({ "message": "Canvas reset successfully" })),
-            )
-        }
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "error": format!("Failed to reset canvas: {}", e) })),
-        ),
+    // Reset the canvas file and reload it
+    let buffer = match init_pixel_file(pixel_file_path, &canvas_size)
+        .and_then(|_| CanvasBuffer::recover(pixel_file_path, &canvas_size))
+    {
+        Ok(buffer) => buffer,
+        Err(e) => return reset_failed(&mut canvas, format!("Failed to reset canvas: {}", e)),
+    };
+
+    // The placement history belongs to the old canvas, archive it next to the backup
+    let history_backup_path = format!("{}-{}", history_path(pixel_file_path), suffix);
+    if let Err(e) = state.history.lock().await.rotate(&history_backup_path) {
+        return reset_failed(
+            &mut canvas,
+            format!("Failed to backup placement log: {}", e),
+        );
+    }
+
+    *canvas = buffer;
+    publish(
+        &state,
+        CanvasEvent::Reset {
+            width: canvas_size.width,
+            height: canvas_size.height,
+        },
+    )
+    .await;
+    // Reset the canvas size in the state
+    *canvas_size_lock = canvas_size;
+    (
+        StatusCode::OK,
+        Json(json!({ "message": "Canvas reset successfully" })),
+    )
+}
+
+/// The new canvas file may already be on disk, put the live canvas back in its place
+fn reset_failed(canvas: &mut CanvasBuffer, message: String) -> (StatusCode, Json<Value>) {
+    let (data, size) = (canvas.data.clone(), canvas.size());
+    if let Err(e) = canvas.replace(data, &size) {
+        tracing::error!("Failed to restore the canvas after a failed reset: {}", e);
     }
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": message })),
+    )
 }
 
 pub async fn admin_update_delay(
@@ -182,7 +337,7 @@ pub async fn admin_update_delay(
     jar: PrivateCookieJar,
     Json(payload): Json<serde_json::Value>,
 ) -> impl IntoResponse {
-    if !is_user_admin(&jar) {
+    if !is_user_admin(&state, &jar) {
         return (
             StatusCode::UNAUTHORIZED,
             Json(json!({ "error": "Unauthorized" })),
@@ -202,8 +357,109 @@ pub async fn admin_update_delay(
     let mut state_lock = state.delay.lock().await;
     *state_lock = delay;
 
+    if let Err(e) = update_settings(&state, |s| s.delay = Some(delay)).await {
+        tracing::error!("Failed to persist delay: {}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Delay updated but not persisted: {}", e) })),
+        );
+    }
+
     (
         StatusCode::OK,
         Json(json!({ "message": "Delay updated successfully" })),
     )
 }
+
+pub async fn admin_update_bucket_size(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    Json(payload): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    if !is_user_admin(&state, &jar) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Unauthorized" })),
+        );
+    }
+
+    let bucket_size = match payload.get("bucketSize").and_then(|v| v.as_u64()) {
+        Some(size) if (1..=u32::MAX as u64).contains(&size) => size as u32,
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Missing or invalid 'bucketSize' field" })),
+            );
+        }
+    };
+
+    let mut state_lock = state.bucket_size.lock().await;
+    *state_lock = bucket_size;
+
+    if let Err(e) = update_settings(&state, |s| s.bucket_size = Some(bucket_size)).await {
+        tracing::error!("Failed to persist bucket size: {}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Bucket size updated but not persisted: {}", e) })),
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({ "message": "Bucket size updated successfully" })),
+    )
+}
+
+pub async fn admin_update_pow_difficulty(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    Json(payload): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    if !is_user_admin(&state, &jar) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Unauthorized" })),
+        );
+    }
+
+    let difficulty = match payload.get("difficulty").and_then(|v| v.as_u64()) {
+        Some(d) if d <= MAX_DIFFICULTY as u64 => d as u8,
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": format!("'difficulty' must be between 0 and {}", MAX_DIFFICULTY)
+                })),
+            );
+        }
+    };
+
+    let mut state_lock = state.pow_difficulty.lock().await;
+    *state_lock = difficulty;
+
+    if let Err(e) = update_settings(&state, |s| s.pow_difficulty = Some(difficulty)).await {
+        tracing::error!("Failed to persist proof of work difficulty: {}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Difficulty updated but not persisted: {}", e) })),
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({ "message": "Difficulty updated successfully" })),
+    )
+}
+
+pub async fn admin_limiter_metrics(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+) -> impl IntoResponse {
+    if !is_user_admin(&state, &jar) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Unauthorized" })),
+        );
+    }
+    (StatusCode::OK, Json(state.limiter.metrics()))
+}
@@ -0,0 +1,121 @@
+use crate::routes::state::AppState;
+use crate::utils::oidc::PendingLogin;
+use crate::utils::session::USER_COOKIE;
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::{Cookie, PrivateCookieJar, SameSite};
+use serde::Deserialize;
+use serde_json::json;
+
+const LOGIN_COOKIE: &str = "oidc_login";
+const LOGIN_COOKIE_PATH: &str = "/api/login";
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+fn login_error(status: StatusCode, message: String) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+/// Start the OpenID Connect login by redirecting to the provider
+pub async fn login(State(state): State<AppState>, jar: PrivateCookieJar) -> Response {
+    let Some(oidc) = &state.oidc else {
+        return login_error(StatusCode::NOT_FOUND, "Login is not configured".to_string());
+    };
+
+    let (url, pending) = match oidc.authorization_url().await {
+        Ok(login) => login,
+        Err(e) => {
+            tracing::error!("OIDC login failed: {}", e);
+            return login_error(StatusCode::BAD_GATEWAY, e);
+        }
+    };
+
+    let mut cookie = Cookie::new(
+        LOGIN_COOKIE,
+        serde_json::to_string(&pending).expect("PendingLogin serializes"),
+    );
+    cookie.set_http_only(true);
+    // The callback is a top-level navigation coming from the provider
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_max_age(time::Duration::minutes(10));
+    cookie.set_path(LOGIN_COOKIE_PATH);
+
+    (jar.add(cookie), Redirect::to(&url)).into_response()
+}
+
+/// Where the provider sends the browser back with an authorization code
+pub async fn login_callback(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    Query(query): Query<CallbackQuery>,
+) -> Response {
+    let Some(oidc) = &state.oidc else {
+        return login_error(StatusCode::NOT_FOUND, "Login is not configured".to_string());
+    };
+
+    let pending: Option<PendingLogin> = jar
+        .get(LOGIN_COOKIE)
+        .and_then(|cookie| serde_json::from_str(cookie.value()).ok());
+    let jar = jar.remove(Cookie::build(LOGIN_COOKIE).path(LOGIN_COOKIE_PATH));
+
+    if let Some(error) = query.error {
+        return (
+            jar,
+            login_error(
+                StatusCode::UNAUTHORIZED,
+                format!("Login refused: {}", error),
+            ),
+        )
+            .into_response();
+    }
+    let (Some(pending), Some(code)) = (pending, query.code) else {
+        return (
+            jar,
+            login_error(StatusCode::BAD_REQUEST, "No login in progress".to_string()),
+        )
+            .into_response();
+    };
+    if query.state.as_deref() != Some(pending.state.as_str()) {
+        return (
+            jar,
+            login_error(StatusCode::BAD_REQUEST, "Login state mismatch".to_string()),
+        )
+            .into_response();
+    }
+
+    let user = match oidc.authenticate(&code, &pending).await {
+        Ok(user) => user,
+        Err(e) => {
+            tracing::error!("OIDC login failed: {}", e);
+            return (jar, login_error(StatusCode::BAD_GATEWAY, e)).into_response();
+        }
+    };
+    tracing::info!("User {} ({}) logged in", user.id, user.name);
+
+    let mut cookie = Cookie::new(
+        USER_COOKIE,
+        serde_json::to_string(&user).expect("User serializes"),
+    );
+    cookie.set_http_only(true);
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_max_age(time::Duration::days(7));
+    cookie.set_path("/");
+
+    (jar.add(cookie), Redirect::to("/")).into_response()
+}
+
+pub async fn logout(jar: PrivateCookieJar) -> impl IntoResponse {
+    (
+        jar.remove(Cookie::build(USER_COOKIE).path("/")),
+        Json(json!({ "message": "Logged out" })),
+    )
+}
@@ -0,0 +1,156 @@
+use crate::routes::admin::is_user_admin;
+use crate::routes::state::{AppState, BanRequest};
+use crate::utils::bans::Ban;
+use crate::utils::history::now_millis;
+use crate::utils::session::random_token;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use axum_extra::extract::cookie::PrivateCookieJar;
+use serde_json::json;
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+fn unauthorized() -> Response {
+    error(StatusCode::UNAUTHORIZED, "Unauthorized")
+}
+
+fn not_found() -> Response {
+    error(StatusCode::NOT_FOUND, "No such ban")
+}
+
+fn persist_error(e: std::io::Error) -> Response {
+    tracing::error!("Failed to save the bans: {}", e);
+    error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        &format!("Failed to save the bans: {}", e),
+    )
+}
+
+fn expires_in_past(request: &BanRequest, now: u64) -> bool {
+    request.expires_at.is_some_and(|expiry| expiry <= now)
+}
+
+pub async fn list_bans(State(state): State<AppState>, jar: PrivateCookieJar) -> Response {
+    if !is_user_admin(&state, &jar) {
+        return unauthorized();
+    }
+    let bans = state.bans.lock().await;
+    let now = now_millis();
+    // Expired bans linger in the file until the next change, don't show them
+    let active: Vec<&Ban> = bans
+        .list()
+        .iter()
+        .filter(|ban| ban.is_active(now))
+        .collect();
+    Json(active).into_response()
+}
+
+pub async fn get_ban(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    Path(id): Path<String>,
+) -> Response {
+    if !is_user_admin(&state, &jar) {
+        return unauthorized();
+    }
+    // Same as the list: an expired ban that wasn't dropped yet is gone
+    match state.bans.lock().await.get(&id) {
+        Some(ban) if ban.is_active(now_millis()) => Json(ban).into_response(),
+        _ => not_found(),
+    }
+}
+
+pub async fn create_ban(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    Json(request): Json<BanRequest>,
+) -> Response {
+    if !is_user_admin(&state, &jar) {
+        return unauthorized();
+    }
+    let now = now_millis();
+    if expires_in_past(&request, now) {
+        return error(StatusCode::BAD_REQUEST, "Expiry is in the past");
+    }
+
+    let ban = Ban {
+        id: random_token(),
+        target: request.target,
+        shadow: request.shadow,
+        reason: request.reason,
+        created_at: now,
+        expires_at: request.expires_at,
+    };
+    let mut bans = state.bans.lock().await;
+    if let Err(e) = bans.update(now, |bans| bans.push(ban.clone())) {
+        return persist_error(e);
+    }
+
+    tracing::info!("Ban {} created", ban.id);
+    (StatusCode::CREATED, Json(ban)).into_response()
+}
+
+/// Replace everything but the id and creation time
+pub async fn update_ban(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    Path(id): Path<String>,
+    Json(request): Json<BanRequest>,
+) -> Response {
+    if !is_user_admin(&state, &jar) {
+        return unauthorized();
+    }
+    let now = now_millis();
+    if expires_in_past(&request, now) {
+        return error(StatusCode::BAD_REQUEST, "Expiry is in the past");
+    }
+
+    let mut bans = state.bans.lock().await;
+    let Some(existing) = bans.get(&id) else {
+        return not_found();
+    };
+    let ban = Ban {
+        id: id.clone(),
+        target: request.target,
+        shadow: request.shadow,
+        reason: request.reason,
+        created_at: existing.created_at,
+        expires_at: request.expires_at,
+    };
+    let result = bans.update(now, |bans| {
+        for entry in bans.iter_mut().filter(|entry| entry.id == id) {
+            *entry = ban.clone();
+        }
+    });
+    if let Err(e) = result {
+        return persist_error(e);
+    }
+
+    Json(ban).into_response()
+}
+
+pub async fn delete_ban(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    Path(id): Path<String>,
+) -> Response {
+    if !is_user_admin(&state, &jar) {
+        return unauthorized();
+    }
+
+    let mut bans = state.bans.lock().await;
+    if bans.get(&id).is_none() {
+        return not_found();
+    }
+    if let Err(e) = bans.update(now_millis(), |bans| bans.retain(|ban| ban.id != id)) {
+        return persist_error(e);
+    }
+
+    tracing::info!("Ban {} lifted", id);
+    Json(json!({ "message": "Ban lifted" })).into_response()
+}
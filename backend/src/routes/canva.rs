@@ -1,5 +1,17 @@
 use crate::routes::state::{AppState, CanvasSize};
-use axum::{extract::State, response::Json};
+use crate::utils::history::now_millis;
+use crate::utils::limiter::{cooldown_json, credit_status, remaining_cooldown};
+use crate::utils::pow::CHALLENGE_TTL;
+use crate::utils::requests::get_ip;
+use crate::utils::session::{AnonymousSession, actor_id, current_user};
+use axum::{
+    Extension,
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
+    response::Json,
+};
+use axum_extra::extract::cookie::PrivateCookieJar;
+use std::net::SocketAddr;
 
 pub async fn get_canvas_size(State(state): State<AppState>) -> Json<CanvasSize> {
     let canvas_size = state.canvas_size.lock().await;
@@ -24,3 +36,49 @@ pub async fn get_active(State(state): State<AppState>) -> Json<serde_json::Value
     let active = state.active.lock().await;
     Json(serde_json::json!({ "active": *active }))
 }
+
+/// Placement credits of the caller, see `[limits] bucket_size`
+pub async fn get_credits(
+    State(state): State<AppState>,
+    Extension(session): Extension<AnonymousSession>,
+    jar: PrivateCookieJar,
+) -> Json<serde_json::Value> {
+    let actor = actor_id(current_user(&jar).as_ref(), &session);
+    let status = credit_status(&actor, &state).await;
+    Json(serde_json::json!({
+        "credits": status.credits,
+        "bucketSize": status.bucket_size,
+        "nextCreditIn": status.next_credit_in.map(|d| d.as_millis() as u64), // milliseconds
+    }))
+}
+
+/// How long before the caller may place a pixel, in the shape of a 429 body
+pub async fn get_cooldown(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Extension(session): Extension<AnonymousSession>,
+    headers: HeaderMap,
+    jar: PrivateCookieJar,
+) -> Json<serde_json::Value> {
+    let actor = actor_id(current_user(&jar).as_ref(), &session);
+    let ip = get_ip(&headers, peer, &state.trusted_proxies);
+    let wait = remaining_cooldown(&actor, ip, &state).await;
+    Json(cooldown_json(wait))
+}
+
+/// Proof-of-work challenge to solve and send with the next placement
+pub async fn get_challenge(
+    State(state): State<AppState>,
+    Extension(session): Extension<AnonymousSession>,
+) -> Json<serde_json::Value> {
+    let difficulty = *state.pow_difficulty.lock().await;
+    if difficulty == 0 {
+        return Json(serde_json::json!({ "challenge": null, "difficulty": 0 }));
+    }
+    let now = now_millis();
+    Json(serde_json::json!({
+        "challenge": state.pow.issue(&session.0, difficulty, now),
+        "difficulty": difficulty, // leading zero bits of sha256("<challenge>:<nonce>")
+        "expiresAt": now + CHALLENGE_TTL,
+    }))
+}
@@ -0,0 +1,157 @@
+use crate::routes::admin::is_user_admin;
+use crate::routes::state::{AppState, CANVAS_VERSION_HEADER, CanvasImageQuery, TimelapseQuery};
+use crate::utils::canvas::canvas_etag;
+use crate::utils::events::canvas_version;
+use crate::utils::history::{PlacementLog, history_path};
+use crate::utils::render::{MAX_SCALE, Region, encode_png, render_indexed};
+use crate::utils::requests::if_none_match;
+use crate::utils::timelapse::{TimelapseOptions, render_timelapse};
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Json, Response},
+};
+use axum_extra::extract::cookie::PrivateCookieJar;
+use serde_json::json;
+
+/// How long shared caches may serve a rendered canvas without revalidating
+const CACHE_CONTROL: &str = "public, max-age=10";
+
+fn bad_request(message: &str) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": message }))).into_response()
+}
+
+pub async fn get_canvas_png(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Query(query): Query<CanvasImageQuery>,
+) -> Response {
+    let (version, size, data) = {
+        let canvas = state.canvas.lock().await;
+        (
+            canvas_version(&state).await,
+            canvas.size(),
+            canvas.data.clone(),
+        )
+    };
+
+    let scale = query.scale.unwrap_or(1);
+    if scale == 0 || scale > MAX_SCALE {
+        return bad_request(&format!("scale must be between 1 and {}", MAX_SCALE));
+    }
+
+    let region = match (query.x_start, query.y_start, query.x_end, query.y_end) {
+        (None, None, None, None) => Region::full(&size),
+        (Some(x_start), Some(y_start), Some(x_end), Some(y_end)) => Region {
+            x_start,
+            y_start,
+            x_end,
+            y_end,
+        },
+        _ => return bad_request("xStart, yStart, xEnd and yEnd must be given together"),
+    };
+    if !region.fits(&size) {
+        return bad_request("Region out of bounds");
+    }
+
+    let Some((out_width, out_height)) = region.output_size(scale) else {
+        return bad_request("Requested image is too large");
+    };
+
+    // The query is part of the URL, so the canvas content alone identifies the image
+    let etag = canvas_etag(&data);
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, CACHE_CONTROL.to_string()),
+    ];
+
+    if if_none_match(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+
+    let indexed = render_indexed(&data, size.width, &region, scale);
+    match encode_png(&indexed, out_width, out_height) {
+        Ok(png) => (
+            [(header::CONTENT_TYPE, "image/png")],
+            cache_headers,
+            [(CANVAS_VERSION_HEADER, version.to_string())],
+            png,
+        )
+            .into_response(),
+        Err(e) => {
+            tracing::error!("Failed to encode canvas PNG: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to render canvas" })),
+            )
+                .into_response()
+        }
+    }
+}
+
+pub async fn admin_timelapse(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    Query(query): Query<TimelapseQuery>,
+) -> Response {
+    if !is_user_admin(&state, &jar) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Unauthorized" })),
+        )
+            .into_response();
+    }
+
+    let region = match (query.x_start, query.y_start, query.x_end, query.y_end) {
+        (None, None, None, None) => None,
+        (Some(x_start), Some(y_start), Some(x_end), Some(y_end)) => Some(Region {
+            x_start,
+            y_start,
+            x_end,
+            y_end,
+        }),
+        _ => return bad_request("xStart, yStart, xEnd and yEnd must be given together"),
+    };
+    let scale = query.scale.unwrap_or(1);
+    if scale > MAX_SCALE {
+        return bad_request(&format!("scale must be between 1 and {}", MAX_SCALE));
+    }
+    let options = TimelapseOptions {
+        interval_ms: query.interval.unwrap_or(60).saturating_mul(1000),
+        frame_delay: query.frame_delay.unwrap_or(10),
+        scale,
+        region,
+    };
+
+    let size = state.canvas.lock().await.size();
+    let path = history_path(&state.file_path);
+
+    // Read the log back from disk rather than copying it under the history lock, which would
+    // stall placements. Encoding can take a while on long events, keep it off the async workers.
+    let rendered = tokio::task::spawn_blocking(move || {
+        PlacementLog::read(&path)
+            .and_then(|placements| render_timelapse(&placements, &size, &options))
+    })
+    .await;
+
+    match rendered {
+        Ok(Ok(gif)) => ([(header::CONTENT_TYPE, "image/gif")], gif).into_response(),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidInput => bad_request(&e.to_string()),
+        Ok(Err(e)) => {
+            tracing::error!("Failed to render timelapse: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to render timelapse" })),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            tracing::error!("Timelapse rendering task failed: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to render timelapse" })),
+            )
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use crate::routes::state::AppState;
+use crate::utils::events::{EventEnvelope, subscribe_since};
+use axum::{
+    extract::{
+        State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::HeaderMap,
+    response::{
+        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use serde_json::json;
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::{
+    Stream, StreamExt,
+    wrappers::{BroadcastStream, errors::BroadcastStreamRecvError},
+};
+
+pub async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, state))
+}
+
+async fn handle_socket(mut socket: WebSocket, state: AppState) {
+    let mut events = state.events.subscribe();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let text = match event {
+                    Ok(event) => match serde_json::to_string(&event) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            tracing::error!("Failed to serialize canvas event: {}", e);
+                            continue;
+                        }
+                    },
+                    // The client fell behind, it has to reload the whole canvas
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("WebSocket client lagged behind by {} events", skipped);
+                        json!({ "type": "resync" }).to_string()
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                match message {
+                    // Clients only listen, incoming messages are ignored
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+fn sse_event(envelope: &EventEnvelope) -> Event {
+    Event::default()
+        .id(envelope.id.to_string())
+        .json_data(&envelope.event)
+        .unwrap_or_else(|_| resync_event())
+}
+
+fn resync_event() -> Event {
+    Event::default().data(json!({ "type": "resync" }).to_string())
+}
+
+pub async fn sse_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let last_id = headers
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+
+    let (missed, receiver) = subscribe_since(&state, last_id).await;
+
+    // Too far behind to replay: the client has to reload the whole canvas
+    let replay: Vec<Event> = match missed {
+        Some(missed) => missed.iter().map(sse_event).collect(),
+        None => vec![resync_event()],
+    };
+
+    let live = BroadcastStream::new(receiver).map(|event| match event {
+        Ok(envelope) => sse_event(&envelope),
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+            tracing::warn!("SSE client lagged behind by {} events", skipped);
+            resync_event()
+        }
+    });
+
+    let stream = tokio_stream::iter(replay).chain(live).map(Ok);
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
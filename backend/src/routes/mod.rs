@@ -1,4 +1,9 @@
 pub mod admin;
+pub mod auth;
+pub mod bans;
 pub mod canva;
+pub mod image;
+pub mod live;
+pub mod moderation;
 pub mod pixel;
 pub mod state;
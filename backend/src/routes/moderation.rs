@@ -0,0 +1,233 @@
+use crate::routes::admin::is_user_admin;
+use crate::routes::state::{
+    AppState, COLORS, CanvasEvent, PixelChange, RevertActorRequest, RollbackRequest,
+};
+use crate::utils::canvas::CanvasBuffer;
+use crate::utils::events::publish;
+use crate::utils::history::{ADMIN_ACTOR, Placement, now_millis, record_placements};
+use crate::utils::requests::hash_ip;
+use crate::utils::wal::WalOp;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json},
+};
+use axum_extra::extract::cookie::PrivateCookieJar;
+use serde_json::json;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+
+/// Pixel to put back: coordinates and color index
+type Restoration = (u32, u32, u8);
+
+/// Write older colors back through the canvas store, log them as admin placements
+/// and broadcast them as a single event. The caller holds the canvas lock.
+async fn restore_pixels(
+    state: &AppState,
+    canvas: &mut CanvasBuffer,
+    restorations: &[Restoration],
+) -> std::io::Result<()> {
+    if restorations.is_empty() {
+        return Ok(());
+    }
+
+    let width = canvas.size().width;
+    let timestamp = now_millis();
+    let placements: Vec<Placement> = restorations
+        .iter()
+        .map(|&(x, y, color)| Placement {
+            timestamp,
+            x,
+            y,
+            color,
+            actor: ADMIN_ACTOR.to_string(),
+            ip_hash: String::new(),
+            previous: Some(canvas.get(y * width + x)),
+        })
+        .collect();
+
+    let ops: Vec<WalOp> = restorations
+        .iter()
+        .map(|&(x, y, color_index)| WalOp::Pixel { x, y, color_index })
+        .collect();
+    canvas.write_all(&ops)?;
+    record_placements(state, &placements).await;
+
+    let pixels = restorations
+        .iter()
+        .map(|&(x, y, color)| PixelChange {
+            x,
+            y,
+            color: COLORS[color as usize].to_string(),
+        })
+        .collect();
+    publish(state, CanvasEvent::Restore { pixels }).await;
+
+    Ok(())
+}
+
+/// Put a rectangle back to the colors it had at `timestamp`
+pub async fn admin_rollback(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    Json(payload): Json<RollbackRequest>,
+) -> impl IntoResponse {
+    if !is_user_admin(&state, &jar) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Unauthorized" })),
+        );
+    }
+
+    let start = payload.range.start;
+    let end = payload.range.end;
+    let x_min = start.x.min(end.x);
+    let y_min = start.y.min(end.y);
+    let x_max = start.x.max(end.x);
+    let y_max = start.y.max(end.y);
+
+    let size = state.canvas_size.lock().await;
+    if x_max >= size.width || y_max >= size.height {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Invalid coordinates for rollback" })),
+        );
+    }
+
+    let mut canvas = state.canvas.lock().await;
+    let restorations: Vec<Restoration> = {
+        let history = state.history.lock().await;
+        (y_min..=y_max)
+            .flat_map(|y| (x_min..=x_max).map(move |x| (x, y)))
+            .filter_map(|(x, y)| {
+                let placements: Vec<&Placement> = history.pixel_history(x, y).collect();
+                let changed_at = placements.partition_point(|p| p.timestamp <= payload.timestamp);
+                // Untouched since then, so it already has the color it had then
+                if changed_at == placements.len() {
+                    return None;
+                }
+                // The color overwritten by the first change since then, for lines logged
+                // before it was recorded the last color placed until then, or else white
+                let color = placements[changed_at]
+                    .previous
+                    .or_else(|| changed_at.checked_sub(1).map(|i| placements[i].color))
+                    .unwrap_or(0);
+                (canvas.get(y * size.width + x) != color).then_some((x, y, color))
+            })
+            .collect()
+    };
+
+    tracing::info!(
+        "Admin rolled back ({}, {})-({}, {}) to {}, {} pixels changed",
+        x_min,
+        y_min,
+        x_max,
+        y_max,
+        payload.timestamp,
+        restorations.len()
+    );
+
+    match restore_pixels(&state, &mut canvas, &restorations).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(
+                json!({ "message": "Area rolled back successfully", "restored": restorations.len() }),
+            ),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Failed to roll back pixels: {}", e) })),
+        ),
+    }
+}
+
+/// Undo every placement of an actor: each pixel they touched gets the most recent color
+/// placed there by someone else, or the color it had before the actor's first placement
+pub async fn admin_revert_actor(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    Json(payload): Json<RevertActorRequest>,
+) -> impl IntoResponse {
+    if !is_user_admin(&state, &jar) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Unauthorized" })),
+        );
+    }
+
+    // An actor id or IP hash as shown in the pixel history, or a raw IP to hash
+    let target = match (payload.actor, payload.ip) {
+        (Some(actor), None) if !actor.is_empty() => actor,
+        (None, Some(ip)) if !ip.is_empty() => match ip.trim().parse::<IpAddr>() {
+            Ok(ip) => hash_ip(&state, ip),
+            Err(_) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "error": "Invalid IP address" })),
+                );
+            }
+        },
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Give either 'actor' or 'ip'" })),
+            );
+        }
+    };
+    let is_target = |p: &Placement| p.actor == target || p.ip_hash == target;
+    let since = payload.since.unwrap_or(0);
+
+    let size = state.canvas_size.lock().await;
+    let mut canvas = state.canvas.lock().await;
+    let restorations: Vec<Restoration> = {
+        let history = state.history.lock().await;
+        let touched: BTreeSet<(u32, u32)> = history
+            .entries
+            .iter()
+            .filter(|p| p.timestamp >= since && is_target(p))
+            .map(|p| (p.x, p.y))
+            .collect();
+
+        touched
+            .into_iter()
+            .filter(|&(x, y)| x < size.width && y < size.height)
+            .filter_map(|(x, y)| {
+                // Nobody else placed here: the color from before the pixel's first logged
+                // placement, white when that wasn't recorded yet
+                let color = match history.pixel_history(x, y).filter(|p| !is_target(p)).last() {
+                    Some(placement) => placement.color,
+                    None => history
+                        .pixel_history(x, y)
+                        .next()
+                        .and_then(|p| p.previous)
+                        .unwrap_or(0),
+                };
+                (canvas.get(y * size.width + x) != color).then_some((x, y, color))
+            })
+            .collect()
+    };
+
+    if payload.dry_run {
+        return (
+            StatusCode::OK,
+            Json(json!({ "dryRun": true, "affected": restorations.len() })),
+        );
+    }
+
+    tracing::info!(
+        "Admin reverted an actor since {}, {} pixels changed",
+        since,
+        restorations.len()
+    );
+
+    match restore_pixels(&state, &mut canvas, &restorations).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(json!({ "dryRun": false, "affected": restorations.len() })),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Failed to revert pixels: {}", e) })),
+        ),
+    }
+}
@@ -1,51 +1,76 @@
 use crate::routes::admin::is_user_admin;
 use crate::routes::state::{
-    AppState, COLORS, CanvasSize, PixelRange, PixelRegionRequest, PixelRequest,
+    AppState, CANVAS_VERSION_HEADER, COLORS, CanvasEvent, CanvasSize, Pixel, PixelChange,
+    PixelRange, PixelRegionRequest, PixelRequest,
 };
 
-use crate::utils::requests::{get_ip, is_request_allowed};
+use crate::utils::canvas::{canvas_etag, write_atomically};
+use crate::utils::events::{canvas_version, publish};
+use crate::utils::history::{ADMIN_ACTOR, Actor, Placement, now_millis, record_placements};
+use crate::utils::limiter::{is_request_allowed, too_many_requests};
+use crate::utils::pixel_file;
+use crate::utils::requests::{ByteRange, anonymize, get_ip, hash_ip, if_none_match, parse_range};
+use crate::utils::session::{AnonymousSession, actor_id, current_user};
+use crate::utils::wal::WalOp;
 use axum::{
-    extract::State,
-    http::{HeaderMap, StatusCode},
-    response::{IntoResponse, Json},
+    Extension,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, HeaderName, StatusCode, header},
+    response::{IntoResponse, Json, Response},
 };
 use axum_extra::extract::cookie::PrivateCookieJar;
 use serde_json::json;
-use std::fs::{self, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
 
 async fn write_pixel_data_locked(
     x: &u32,
     y: &u32,
     color_index: &u8,
+    actor: &Actor,
     size: &CanvasSize,
     state: &AppState,
 ) -> std::io::Result<()> {
-    // Compute pixel index and offset
-    let pixel_index = (y * size.width + x) as u64;
-    let offset = pixel_index / 2; // Two pixels per byte
-    let file_path = &state.file_path;
-    // Locking file access
-    let _guard = state.file_lock.lock().await;
-
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(file_path.as_str())?;
-
-    file.seek(SeekFrom::Start(offset))?;
-    let mut byte = [0u8];
-    file.read_exact(&mut byte)?;
-    file.seek(SeekFrom::Start(offset))?;
-
-    let updated_byte = if pixel_index % 2 == 0 {
-        (byte[0] & 0x0F) | (color_index << 4)
-    } else {
-        (byte[0] & 0xF0) | color_index
-    };
+    if *x >= size.width || *y >= size.height {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Pixel out of bounds",
+        ));
+    }
 
-    file.write_all(&[updated_byte])?;
+    let mut canvas = state.canvas.lock().await;
+    let previous = canvas.get(*y * size.width + *x);
+    canvas.write(WalOp::Pixel {
+        x: *x,
+        y: *y,
+        color_index: *color_index,
+    })?;
+
+    record_placements(
+        state,
+        &[Placement {
+            timestamp: now_millis(),
+            x: *x,
+            y: *y,
+            color: *color_index,
+            actor: actor.id.clone(),
+            ip_hash: actor.ip_hash.clone(),
+            previous: Some(previous),
+        }],
+    )
+    .await;
+
+    // Bump the canvas version while the canvas is still locked
+    publish(
+        state,
+        CanvasEvent::Pixel {
+            x: *x,
+            y: *y,
+            color: COLORS[*color_index as usize].to_string(),
+        },
+    )
+    .await;
 
     Ok(())
 }
@@ -58,24 +83,81 @@ fn nibble_to_hex(n: u8) -> char {
     }
 }
 
+fn proof_refused(error: &str) -> Response {
+    (StatusCode::FORBIDDEN, Json(json!({ "error": error }))).into_response()
+}
+
 pub async fn handle_pixel_request(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Extension(session): Extension<AnonymousSession>,
     headers: HeaderMap,
     State(state): State<AppState>,
+    jar: PrivateCookieJar,
     Json(request): Json<PixelRequest>,
-) -> impl IntoResponse {
-    let active_guard = state.active.lock().await;
-    if !*active_guard {
+) -> Response {
+    let active = *state.active.lock().await;
+    if !active {
         return (
             StatusCode::SERVICE_UNAVAILABLE,
             Json("Service is currently unavailable"),
-        );
+        )
+            .into_response();
+    }
+    let user = current_user(&jar);
+    if user.is_none() && state.oidc.as_ref().is_some_and(|oidc| oidc.require_login()) {
+        return (StatusCode::UNAUTHORIZED, Json("Log in to place pixels")).into_response();
     }
-    let ip = get_ip(&headers);
-    if !is_request_allowed(&ip, &state).await {
+    let actor_id = actor_id(user.as_ref(), &session);
+
+    let ip = get_ip(&headers, peer, &state.trusted_proxies);
+    let prefix = state.limiter.prefix_of(ip);
+
+    let ban = state
+        .bans
+        .lock()
+        .await
+        .find(
+            &session.0,
+            user.as_ref().map(|u| u.id.as_str()),
+            ip,
+            prefix,
+            now_millis(),
+        )
+        .cloned();
+    if let Some(ban) = ban.as_ref().filter(|ban| !ban.shadow) {
         return (
-            StatusCode::TOO_MANY_REQUESTS,
-            Json("Too many requests, please try again later"),
-        );
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "error": "You are banned",
+                "reason": ban.reason,
+                "expiresAt": ban.expires_at,
+            })),
+        )
+            .into_response();
+    }
+    // Shadow banned clients still go through the cooldown, so nothing looks off to them
+    let shadow_banned = ban.is_some();
+
+    // Checked before the cooldown so bots without a proof don't spend credits,
+    // but only used up once the placement is accepted
+    let difficulty = *state.pow_difficulty.lock().await;
+    let mut proof = None;
+    if difficulty > 0 {
+        let verified = match (&request.challenge, request.nonce) {
+            (Some(challenge), Some(nonce)) => state
+                .pow
+                .verify(&session.0, challenge, nonce, difficulty, now_millis())
+                .map(|expiry| (challenge, expiry)),
+            _ => Err("Proof of work required"),
+        };
+        match verified {
+            Ok(verified) => proof = Some(verified),
+            Err(e) => return proof_refused(e),
+        }
+    }
+
+    if let Err(wait) = is_request_allowed(&actor_id, ip, &state).await {
+        return too_many_requests(wait, "Too many requests, please try again later");
     }
 
     let size_guard = state.canvas_size.lock().await;
@@ -89,16 +171,38 @@ pub async fn handle_pixel_request(
             .position(|&c| c == request.color)
             .expect("Color not found in COLORS array") as u8;
 
-        match write_pixel_data_locked(&request.x, &request.y, &color_index, &size_guard, &state)
-            .await
+        if let Some(Err(e)) = proof.map(|(challenge, expiry)| state.pow.burn(challenge, expiry)) {
+            return proof_refused(e);
+        }
+
+        if shadow_banned {
+            return (StatusCode::OK, Json("Pixel updated successfully")).into_response();
+        }
+
+        let ip_hash = hash_ip(&state, ip);
+        let actor = Actor {
+            id: actor_id,
+            ip_hash,
+        };
+
+        match write_pixel_data_locked(
+            &request.x,
+            &request.y,
+            &color_index,
+            &actor,
+            &size_guard,
+            &state,
+        )
+        .await
         {
-            Ok(_) => (StatusCode::OK, Json("Pixel updated successfully")),
+            Ok(_) => (StatusCode::OK, Json("Pixel updated successfully")).into_response(),
             Err(e) => {
                 tracing::error!("Failed to write pixel data: {}", e);
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
                     Json("Failed to write pixel"),
                 )
+                    .into_response()
             }
         }
     } else {
@@ -107,47 +211,196 @@ pub async fn handle_pixel_request(
             request.x,
             request.y
         );
-        (StatusCode::BAD_REQUEST, Json("Invalid pixel data"))
+        (StatusCode::BAD_REQUEST, Json("Invalid pixel data")).into_response()
     }
 }
 
-pub async fn get_all_pixels(State(state): State<AppState>) -> Json<String> {
-    let file_path = &state.file_path;
-    match fs::read(file_path.as_str()) {
-        Ok(data) => {
-            let hex_string = data
-                .iter()
-                .flat_map(|byte| {
-                    let hi = byte >> 4;
-                    let lo = byte & 0x0F;
-                    [nibble_to_hex(hi), nibble_to_hex(lo)]
-                })
-                .collect();
-            Json(hex_string)
+/// Copy the canvas along with the version it corresponds to
+async fn read_canvas(state: &AppState) -> (u64, Vec<u8>) {
+    let canvas = state.canvas.lock().await;
+    let version = canvas_version(state).await;
+    (version, canvas.data.clone())
+}
+
+pub async fn get_all_pixels(State(state): State<AppState>) -> impl IntoResponse {
+    let (version, data) = read_canvas(&state).await;
+    let hex_string: String = data
+        .iter()
+        .flat_map(|byte| {
+            let hi = byte >> 4;
+            let lo = byte & 0x0F;
+            [nibble_to_hex(hi), nibble_to_hex(lo)]
+        })
+        .collect();
+    (
+        [(CANVAS_VERSION_HEADER, version.to_string())],
+        Json(hex_string),
+    )
+}
+
+/// Raw packed canvas (two pixels per byte, high nibble first), with ETag and Range support
+pub async fn get_pixels_binary(headers: HeaderMap, State(state): State<AppState>) -> Response {
+    let (version, data) = read_canvas(&state).await;
+    let etag = canvas_etag(&data);
+    let len = data.len();
+
+    let common_headers = [
+        (header::ETAG, etag.clone()),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+        (header::CACHE_CONTROL, "no-cache".to_string()),
+        (
+            HeaderName::from_static(CANVAS_VERSION_HEADER),
+            version.to_string(),
+        ),
+    ];
+
+    if if_none_match(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, common_headers).into_response();
+    }
+
+    // A range only applies to the representation the client got it from
+    let range_applies = headers
+        .get(header::IF_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .is_none_or(|tag| tag.trim() == etag);
+    let range = if range_applies {
+        parse_range(&headers, len)
+    } else {
+        ByteRange::Full
+    };
+
+    match range {
+        ByteRange::Full => (
+            common_headers,
+            [(header::CONTENT_TYPE, "application/octet-stream")],
+            data,
+        )
+            .into_response(),
+        ByteRange::Partial { start, end } => (
+            StatusCode::PARTIAL_CONTENT,
+            common_headers,
+            [
+                (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+                (
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, len),
+                ),
+            ],
+            data[start..=end].to_vec(),
+        )
+            .into_response(),
+        ByteRange::Unsatisfiable => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            common_headers,
+            [(header::CONTENT_RANGE, format!("bytes */{}", len))],
+        )
+            .into_response(),
+    }
+}
+
+/// Current color of a pixel and who placed it: full identity for admins, a pseudonym otherwise
+pub async fn get_pixel_info(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    Path((x, y)): Path<(u32, u32)>,
+) -> impl IntoResponse {
+    let color = {
+        let canvas = state.canvas.lock().await;
+        let size = canvas.size();
+        if x >= size.width || y >= size.height {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Pixel out of bounds" })),
+            );
         }
-        Err(_) => Json(String::new()),
+        COLORS[canvas.get(y * size.width + x) as usize]
+    };
+
+    let history = state.history.lock().await;
+    let Some(placement) = history.last_placement(x, y) else {
+        return (
+            StatusCode::OK,
+            Json(json!({ "x": x, "y": y, "color": color, "placedAt": null })),
+        );
+    };
+
+    let info = if is_user_admin(&state, &jar) {
+        json!({
+            "x": x,
+            "y": y,
+            "color": color,
+            "placedAt": placement.timestamp,
+            "actor": placement.actor,
+            "ipHash": placement.ip_hash,
+        })
+    } else {
+        json!({
+            "x": x,
+            "y": y,
+            "color": color,
+            "placedAt": placement.timestamp,
+            "placer": anonymize(&state, &placement.actor),
+        })
+    };
+    (StatusCode::OK, Json(info))
+}
+
+/// Every placement at a pixel, for moderators investigating a raid
+pub async fn admin_pixel_history(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    Path((x, y)): Path<(u32, u32)>,
+) -> impl IntoResponse {
+    if !is_user_admin(&state, &jar) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Unauthorized" })),
+        );
+    }
+
+    let size = state.canvas_size.lock().await.clone();
+    if x >= size.width || y >= size.height {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Pixel out of bounds" })),
+        );
     }
+
+    let history = state.history.lock().await;
+    let placements: Vec<_> = history
+        .pixel_history(x, y)
+        .map(|placement| {
+            json!({
+                "timestamp": placement.timestamp,
+                "color": COLORS.get(placement.color as usize),
+                "actor": placement.actor,
+                "ipHash": placement.ip_hash,
+            })
+        })
+        .collect();
+
+    (
+        StatusCode::OK,
+        Json(json!({ "x": x, "y": y, "placements": placements })),
+    )
 }
 
 pub async fn get_pixel_region(
     State(state): State<AppState>,
     Json(region): Json<PixelRegionRequest>,
-) -> impl IntoResponse {
+) -> Response {
     let size_guard = state.canvas_size.lock().await;
-    let file_path = &state.file_path;
 
-    let Ok(data) = fs::read(file_path.as_str()) else {
-        return (StatusCode::INTERNAL_SERVER_ERROR, Json(String::new()));
-    };
+    let (version, data) = read_canvas(&state).await;
 
-    let x_start = region.xStart;
-    let x_end = region.xEnd;
-    let y_start = region.yStart;
-    let y_end = region.yEnd;
+    let x_start = region.x_start;
+    let x_end = region.x_end;
+    let y_start = region.y_start;
+    let y_end = region.y_end;
 
     if x_start > x_end || y_start > y_end || x_end >= size_guard.width || y_end >= size_guard.height
     {
-        return (StatusCode::BAD_REQUEST, Json(String::new()));
+        return (StatusCode::BAD_REQUEST, Json(String::new())).into_response();
     }
 
     let mut hex_string =
@@ -157,7 +410,7 @@ pub async fn get_pixel_region(
         for x in x_start..=x_end {
             let idx = y * size_guard.width + x;
             let byte_index = idx / 2;
-            let high_nibble = idx % 2 == 0;
+            let high_nibble = idx.is_multiple_of(2);
 
             if let Some(byte) = data.get(byte_index as usize) {
                 let nibble = if high_nibble { byte >> 4 } else { byte & 0x0F };
@@ -166,28 +419,98 @@ pub async fn get_pixel_region(
         }
     }
 
-    (StatusCode::OK, Json(hex_string))
+    (
+        StatusCode::OK,
+        [(CANVAS_VERSION_HEADER, version.to_string())],
+        Json(hex_string),
+    )
+        .into_response()
+}
+
+pub async fn get_pixels_since(
+    State(state): State<AppState>,
+    Path(version): Path<u64>,
+) -> impl IntoResponse {
+    let log = state.event_log.lock().await;
+    let current_version = log.current_id();
+
+    let too_old = (
+        StatusCode::GONE,
+        Json(json!({
+            "error": "Version too old, refetch the canvas",
+            "version": current_version,
+        })),
+    );
+
+    let Some(events) = log.since(version) else {
+        return too_old;
+    };
+    drop(log);
+
+    // Only keep the latest color of each pixel
+    let mut changes: Vec<PixelChange> = Vec::new();
+    let mut positions: HashMap<(u32, u32), usize> = HashMap::new();
+    let mut set_pixel = |x: u32, y: u32, color: &str| match positions.get(&(x, y)) {
+        Some(&i) => changes[i].color = color.to_string(),
+        None => {
+            positions.insert((x, y), changes.len());
+            changes.push(PixelChange {
+                x,
+                y,
+                color: color.to_string(),
+            });
+        }
+    };
+
+    for envelope in events {
+        match envelope.event {
+            CanvasEvent::Pixel { x, y, color } => set_pixel(x, y, &color),
+            CanvasEvent::Restore { pixels } => {
+                for pixel in pixels {
+                    set_pixel(pixel.x, pixel.y, &pixel.color);
+                }
+            }
+            CanvasEvent::Whitening { start, end } => {
+                for y in start.y..=end.y {
+                    for x in start.x..=end.x {
+                        set_pixel(x, y, COLORS[0]);
+                    }
+                }
+            }
+            // The canvas layout changed, deltas can't describe that
+            CanvasEvent::Reset { .. } | CanvasEvent::Resize { .. } => return too_old,
+        }
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({ "version": current_version, "pixels": changes })),
+    )
 }
 
 #[allow(dead_code)]
-pub fn init_pixel_file(path: &str, size: &CanvasSize) -> std::io::Result<()> {
-    if size.width % 2 != 0 || size.height % 2 != 0 {
+/// Two pixels are packed per byte, so canvases need even sides
+pub fn validate_canvas_size(size: &CanvasSize) -> std::io::Result<()> {
+    if !size.width.is_multiple_of(2) || !size.height.is_multiple_of(2) {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
             "Width and height must be even numbers",
         ));
     }
+    Ok(())
+}
+
+pub fn init_pixel_file(path: &str, size: &CanvasSize) -> std::io::Result<()> {
+    validate_canvas_size(size)?;
 
     let byte_count = (size.width * size.height) / 2;
     let buffer = vec![0u8; byte_count as usize];
 
-    if let Some(parent) = Path::new(path).parent() {
+    if let Some(parent) = std::path::Path::new(path).parent() {
         fs::create_dir_all(parent)?;
     }
 
-    let mut file = fs::File::create(path)?;
-    file.write_all(&buffer)?;
-    Ok(())
+    write_atomically(path, &pixel_file::encode(size, &buffer))
 }
 
 pub async fn admin_whitening(
@@ -195,7 +518,7 @@ pub async fn admin_whitening(
     jar: PrivateCookieJar,
     Json(payload): Json<PixelRange>,
 ) -> impl IntoResponse {
-    if !is_user_admin(&jar) {
+    if !is_user_admin(&state, &jar) {
         return (
             StatusCode::UNAUTHORIZED,
             Json(json!({ "error": "Unauthorized" })),
@@ -230,7 +553,6 @@ async fn whiten_area(
     state: &AppState,
 ) -> std::io::Result<()> {
     let canvas_size = state.canvas_size.lock().await;
-    let file_path = &state.file_path;
     let width = canvas_size.width;
     let height = canvas_size.height;
 
@@ -242,26 +564,36 @@ async fn whiten_area(
         ));
     }
 
-    // Prepare the lock for exclusive file access
-    let _guard = state.file_lock.lock().await;
-
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(file_path.as_str())?;
-
-    let start_offset = (y_min * width + x_min) / 2;
-    let bytes_per_row = ((x_max - x_min) / 2) + 1;
-    let blank_row = vec![0u8; bytes_per_row as usize];
-    let bytes_per_canvas_row = width / 2;
-
-    let mut offset = start_offset;
-
-    for _ in y_min..=y_max {
-        file.seek(SeekFrom::Start(offset as u64))?;
-        file.write_all(&blank_row)?;
-        offset += bytes_per_canvas_row;
-    }
+    let mut canvas = state.canvas.lock().await;
+    let timestamp = now_millis();
+    let placements: Vec<Placement> = (y_min..=y_max)
+        .flat_map(|y| (x_min..=x_max).map(move |x| (x, y)))
+        .map(|(x, y)| Placement {
+            timestamp,
+            x,
+            y,
+            color: 0,
+            actor: ADMIN_ACTOR.to_string(),
+            ip_hash: String::new(),
+            previous: Some(canvas.get(y * width + x)),
+        })
+        .collect();
+    canvas.write(WalOp::Whiten {
+        x_min,
+        y_min,
+        x_max,
+        y_max,
+    })?;
+    record_placements(state, &placements).await;
+
+    publish(
+        state,
+        CanvasEvent::Whitening {
+            start: Pixel { x: x_min, y: y_min },
+            end: Pixel { x: x_max, y: y_max },
+        },
+    )
+    .await;
 
     Ok(())
 }
@@ -272,7 +604,6 @@ pub async fn resize_canvas_locked(
     state: &AppState,
 ) -> std::io::Result<()> {
     let mut canvas_size_guard = state.canvas_size.lock().await;
-    let file_path = &state.file_path;
     let old_width = canvas_size_guard.width;
     let old_height = canvas_size_guard.height;
 
@@ -285,7 +616,7 @@ pub async fn resize_canvas_locked(
     );
 
     // Validation
-    if new_width % 2 != 0 || new_height % 2 != 0 {
+    if !new_width.is_multiple_of(2) || !new_height.is_multiple_of(2) {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
             "Width and height must be even numbers",
@@ -299,44 +630,35 @@ pub async fn resize_canvas_locked(
         ));
     }
 
-    let _file_guard = state.file_lock.lock().await;
-
-    // Step 1: Add padding to each existing row
-    {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(file_path.as_str())?;
-
-        let row_size_old = old_width / 2;
-        let row_padding = (new_width - old_width) / 2;
-        let mut offset = 0u64;
-
-        for _ in 0..old_height {
-            offset += row_size_old as u64;
-            file.seek(SeekFrom::Start(offset))?;
-            let mut rest_of_data = Vec::new();
-            file.read_to_end(&mut rest_of_data)?;
-            file.seek(SeekFrom::Start(offset))?;
-            file.write_all(&vec![0u8; row_padding as usize])?;
-            file.write_all(&rest_of_data)?;
-            offset += row_padding as u64;
-        }
-    }
-
-    // Step 2: Append new rows
-    {
-        let extra_rows = new_height - old_height;
-        let row_size_new = (new_width / 2) as usize;
-        let padding = vec![0u8; extra_rows as usize * row_size_new];
+    let mut canvas = state.canvas.lock().await;
 
-        let mut file = OpenOptions::new().append(true).open(file_path.as_str())?;
-        file.write_all(&padding)?;
+    // Copy each existing row into the larger buffer, new pixels stay white
+    let row_size_old = (old_width / 2) as usize;
+    let row_size_new = (new_width / 2) as usize;
+    let mut data = vec![0u8; row_size_new * new_height as usize];
+    for (y, row) in canvas.data.chunks_exact(row_size_old).enumerate() {
+        data[y * row_size_new..y * row_size_new + row_size_old].copy_from_slice(row);
     }
-
-    // Step 4: Update in-memory state
+    canvas.replace(
+        data,
+        &CanvasSize {
+            width: new_width,
+            height: new_height,
+        },
+    )?;
+
+    // Update in-memory state
     canvas_size_guard.width = new_width;
     canvas_size_guard.height = new_height;
 
+    publish(
+        state,
+        CanvasEvent::Resize {
+            width: new_width,
+            height: new_height,
+        },
+    )
+    .await;
+
     Ok(())
 }
@@ -1,8 +1,19 @@
 use crate::config::AuthConfig;
+use crate::utils::admin_sessions::AdminSessions;
+use crate::utils::bans::{BanList, BanTarget};
+use crate::utils::canvas::CanvasBuffer;
+use crate::utils::events::{EventEnvelope, EventLog};
+use crate::utils::history::PlacementLog;
+use crate::utils::limiter::Limiter;
+use crate::utils::login_guard::LoginGuard;
+use crate::utils::oidc::OidcClient;
+use crate::utils::pow::ProofOfWork;
+use crate::utils::requests::TrustedProxies;
+use crate::utils::settings::RuntimeSettings;
 use axum_extra::extract::cookie::Key;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc, time::SystemTime};
-use tokio::sync::Mutex;
+use std::sync::Arc;
+use tokio::sync::{Mutex, broadcast};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct CanvasSize {
@@ -10,12 +21,18 @@ pub struct CanvasSize {
     pub height: u32,
 }
 
-pub const COLORS: [&'static str; 16] = [
+pub const COLORS: [&str; 16] = [
     "#FFFFFF", "#E4E4E4", "#888888", "#222222", "#FFA7D1", "#E50000", "#E59500", "#A06A42",
     "#E5D900", "#94E044", "#02BE01", "#00D3DD", "#0083C7", "#0000EA", "#CD6EEA", "#820080",
 ];
 
-#[derive(Deserialize)]
+/// Identifies `COLORS` in the `pixels.bin` header, bump it whenever the palette changes
+pub const PALETTE_ID: u8 = 1;
+
+/// Response header carrying the canvas version a read corresponds to
+pub const CANVAS_VERSION_HEADER: &str = "x-canvas-version";
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Pixel {
     pub x: u32,
     pub y: u32,
@@ -27,29 +44,131 @@ pub struct PixelRange {
     pub end: Pixel,
 }
 
+#[derive(Deserialize)]
+pub struct RollbackRequest {
+    #[serde(flatten)]
+    pub range: PixelRange,
+    pub timestamp: u64, // milliseconds since the Unix epoch
+}
+
+/// Identify the actor either by actor id / IP hash or by raw IP
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevertActorRequest {
+    pub actor: Option<String>,
+    pub ip: Option<String>,
+    pub since: Option<u64>, // milliseconds since the Unix epoch
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Body of `POST /api/admin/bans` and `PUT /api/admin/bans/{id}`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BanRequest {
+    pub target: BanTarget,
+    #[serde(default)]
+    pub shadow: bool,
+    pub reason: Option<String>,
+    pub expires_at: Option<u64>, // milliseconds since the Unix epoch
+}
+
 #[derive(Deserialize)]
 pub struct PixelRequest {
     pub x: u32,
     pub y: u32,
     pub color: String,
+    pub challenge: Option<String>, // from `GET /api/challenge`, when proof of work is on
+    pub nonce: Option<u64>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PixelChange {
+    pub x: u32,
+    pub y: u32,
+    pub color: String,
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct PixelRegionRequest {
-    pub xStart: u32,
-    pub yStart: u32,
-    pub xEnd: u32,
-    pub yEnd: u32,
+    pub x_start: u32,
+    pub y_start: u32,
+    pub x_end: u32,
+    pub y_end: u32,
+}
+
+/// Query of `GET /api/canvas.png`, the region mirrors `PixelRegionRequest`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CanvasImageQuery {
+    pub scale: Option<u32>,
+    pub x_start: Option<u32>,
+    pub y_start: Option<u32>,
+    pub x_end: Option<u32>,
+    pub y_end: Option<u32>,
+}
+
+/// Query of `GET /api/admin/timelapse.gif`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelapseQuery {
+    pub interval: Option<u64>,    // seconds of placements per frame
+    pub frame_delay: Option<u16>, // hundredths of a second per frame
+    pub scale: Option<u32>,
+    pub x_start: Option<u32>,
+    pub y_start: Option<u32>,
+    pub x_end: Option<u32>,
+    pub y_end: Option<u32>,
+}
+
+/// Canvas change pushed to every live client (WebSocket and SSE).
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CanvasEvent {
+    Pixel {
+        x: u32,
+        y: u32,
+        color: String,
+    },
+    Whitening {
+        start: Pixel,
+        end: Pixel,
+    },
+    /// Moderation put older colors back
+    Restore {
+        pixels: Vec<PixelChange>,
+    },
+    Reset {
+        width: u32,
+        height: u32,
+    },
+    Resize {
+        width: u32,
+        height: u32,
+    },
 }
 
 #[derive(Clone)]
 pub struct AppState {
     pub canvas_size: Arc<Mutex<CanvasSize>>,
-    pub file_lock: Arc<Mutex<()>>, // dummy mutex for synchronizing file access
-    pub delay: Arc<Mutex<u32>>,    // default delay value in seconds
-    pub ip_timestamps: Arc<Mutex<HashMap<String, SystemTime>>>, // track IP cooldown
+    pub canvas: Arc<Mutex<CanvasBuffer>>, // in-memory canvas, flushed to file_path
+    pub delay: Arc<Mutex<u32>>,           // default delay value in seconds
+    pub limiter: Arc<Limiter>,            // placement credits per actor, placements per IP
+    pub bucket_size: Arc<Mutex<u32>>,
+    pub pow: Arc<ProofOfWork>,
+    pub pow_difficulty: Arc<Mutex<u8>>,
     pub auth: AuthConfig,
     pub cookie_key: Key,
     pub file_path: Arc<String>,
     pub active: Arc<Mutex<bool>>,
+    pub settings: Arc<Mutex<RuntimeSettings>>, // admin changes persisted across restarts
+    pub history: Arc<Mutex<PlacementLog>>,     // every placement, for timelapses
+    pub events: broadcast::Sender<EventEnvelope>, // live canvas updates
+    pub event_log: Arc<Mutex<EventLog>>,       // recent events for resuming clients
+    pub trusted_proxies: Arc<TrustedProxies>,  // allowed to set forwarding headers
+    pub oidc: Option<Arc<OidcClient>>,         // player login, when configured
+    pub bans: Arc<Mutex<BanList>>,
+    pub login_guard: Arc<LoginGuard>, // admin login attempts
+    pub admin_sessions: Arc<AdminSessions>,
 }
@@ -0,0 +1,82 @@
+use crate::utils::session::random_token;
+use dashmap::DashMap;
+use serde::Serialize;
+use std::net::IpAddr;
+
+/// Name of the private cookie holding the admin session id
+pub const ADMIN_COOKIE: &str = "admin";
+/// How long an admin session lasts after logging in, in milliseconds
+pub const ADMIN_SESSION_TTL: u64 = 12 * 60 * 60 * 1000;
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminSession {
+    #[serde(skip)]
+    pub id: String,
+    pub created_at: u64, // milliseconds since the Unix epoch
+    pub expires_at: u64,
+    pub last_seen: u64,
+    pub ip: IpAddr, // address the login came from
+}
+
+/// Admin sessions kept in memory, so restarting the server logs every admin out
+#[derive(Default)]
+pub struct AdminSessions {
+    sessions: DashMap<String, AdminSession>,
+}
+
+impl AdminSessions {
+    pub fn create(&self, ip: IpAddr, now: u64) -> AdminSession {
+        let session = AdminSession {
+            id: random_token(),
+            created_at: now,
+            expires_at: now + ADMIN_SESSION_TTL,
+            last_seen: now,
+            ip,
+        };
+        self.sessions.insert(session.id.clone(), session.clone());
+        session
+    }
+
+    /// Whether `id` is a live session, marking it as seen
+    pub fn touch(&self, id: &str, now: u64) -> bool {
+        let Some(mut session) = self.sessions.get_mut(id) else {
+            return false;
+        };
+        if session.expires_at <= now {
+            drop(session);
+            self.sessions.remove(id);
+            return false;
+        }
+        session.last_seen = now;
+        true
+    }
+
+    pub fn list(&self, now: u64) -> Vec<AdminSession> {
+        let mut sessions: Vec<AdminSession> = self
+            .sessions
+            .iter()
+            .filter(|session| session.expires_at > now)
+            .map(|session| session.clone())
+            .collect();
+        sessions.sort_by_key(|session| session.created_at);
+        sessions
+    }
+
+    pub fn revoke(&self, id: &str) -> bool {
+        self.sessions.remove(id).is_some()
+    }
+
+    pub fn revoke_all(&self) -> usize {
+        let count = self.sessions.len();
+        self.sessions.clear();
+        count
+    }
+
+    /// Forget expired sessions
+    pub fn sweep(&self, now: u64) -> usize {
+        let before = self.sessions.len();
+        self.sessions.retain(|_, session| session.expires_at > now);
+        before.saturating_sub(self.sessions.len())
+    }
+}
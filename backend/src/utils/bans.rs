@@ -0,0 +1,111 @@
+use crate::utils::canvas::write_atomically;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Who a ban applies to
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum BanTarget {
+    Session(String), // anonymous session id, the `<id>` of a `session:<id>` actor
+    User(String),    // OpenID Connect `sub`, the `<sub>` of a `user:<sub>` actor
+    Ip(IpAddr),
+    Prefix(IpNet),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Ban {
+    pub id: String,
+    pub target: BanTarget,
+    /// Placements are answered as if accepted but never written
+    pub shadow: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub created_at: u64, // milliseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>, // milliseconds since the Unix epoch, never when `None`
+}
+
+impl Ban {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expiry| expiry > now)
+    }
+
+    /// `prefix` is the network `ip` is aggregated into: banning an address bans its prefix,
+    /// otherwise an IPv6 client would just move to the next address of its /64
+    fn applies_to(&self, session: &str, user: Option<&str>, ip: IpAddr, prefix: IpNet) -> bool {
+        match &self.target {
+            BanTarget::Session(id) => id == session,
+            BanTarget::User(id) => Some(id.as_str()) == user,
+            BanTarget::Ip(banned) => prefix.contains(banned),
+            BanTarget::Prefix(net) => net.contains(&ip),
+        }
+    }
+}
+
+pub fn bans_path(file_path: &str) -> String {
+    Path::new(file_path)
+        .with_file_name("bans.json")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Bans persisted in `bans.json` next to the canvas. Expired bans are dropped on the next change.
+pub struct BanList {
+    path: String,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let bans = match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(BanList {
+            path: path.to_string(),
+            bans,
+        })
+    }
+
+    pub fn list(&self) -> &[Ban] {
+        &self.bans
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Ban> {
+        self.bans.iter().find(|ban| ban.id == id)
+    }
+
+    /// Active ban matching the client, non-shadow bans first
+    pub fn find(
+        &self,
+        session: &str,
+        user: Option<&str>,
+        ip: IpAddr,
+        prefix: IpNet,
+        now: u64,
+    ) -> Option<&Ban> {
+        self.bans
+            .iter()
+            .filter(|ban| ban.is_active(now) && ban.applies_to(session, user, ip, prefix))
+            .min_by_key(|ban| ban.shadow)
+    }
+
+    /// Apply `change` to a copy of the list and atomically rewrite the file
+    pub fn update(&mut self, now: u64, change: impl FnOnce(&mut Vec<Ban>)) -> std::io::Result<()> {
+        let mut updated = self.bans.clone();
+        change(&mut updated);
+        updated.retain(|ban| ban.is_active(now));
+
+        let data = serde_json::to_vec_pretty(&updated).map_err(std::io::Error::other)?;
+        write_atomically(&self.path, &data)?;
+
+        self.bans = updated;
+        Ok(())
+    }
+}
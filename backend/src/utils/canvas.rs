@@ -0,0 +1,233 @@
+use crate::routes::state::{AppState, CanvasSize};
+use crate::utils::pixel_file::{self, PixelFile};
+use crate::utils::wal::{Wal, WalOp};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// In-memory copy of `pixels.bin`, two pixels per byte (high nibble first).
+/// Every change is appended to a write-ahead log before touching memory,
+/// the background flusher then snapshots the canvas and empties the log.
+pub struct CanvasBuffer {
+    pub data: Vec<u8>,
+    width: u32,
+    height: u32,
+    path: String,
+    wal: Wal,
+    dirty: bool, // changed since the last snapshot
+}
+
+fn wal_path(path: &str) -> String {
+    format!("{}.wal", path)
+}
+
+fn tmp_path(path: &str) -> String {
+    format!("{}.tmp", path)
+}
+
+/// Replace `path` with `data` so that a crash leaves either the old or the new file
+pub fn write_atomically(path: &str, data: &[u8]) -> std::io::Result<()> {
+    let tmp = tmp_path(path);
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+
+    // Make the rename itself durable
+    if let Some(parent) = Path::new(path)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+    {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Strong ETag derived from the canvas content, stable across restarts
+pub fn canvas_etag(data: &[u8]) -> String {
+    format!("\"{:08x}\"", crc32fast::hash(data))
+}
+
+/// Color index of a pixel in a packed buffer
+pub fn get_nibble(data: &[u8], pixel_index: u32) -> u8 {
+    let byte = data.get((pixel_index / 2) as usize).copied().unwrap_or(0);
+    if pixel_index.is_multiple_of(2) {
+        byte >> 4
+    } else {
+        byte & 0x0F
+    }
+}
+
+/// Set the color index of a pixel in a packed buffer
+pub fn set_nibble(data: &mut [u8], pixel_index: u32, color_index: u8) {
+    let byte = &mut data[(pixel_index / 2) as usize];
+    *byte = if pixel_index.is_multiple_of(2) {
+        (*byte & 0x0F) | (color_index << 4)
+    } else {
+        (*byte & 0xF0) | (color_index & 0x0F)
+    };
+}
+
+impl CanvasBuffer {
+    /// Load the last good snapshot, replay the write-ahead log over it and snapshot the result.
+    /// `legacy_size` is only used to migrate files written before the header existed.
+    pub fn recover(path: &str, legacy_size: &CanvasSize) -> std::io::Result<Self> {
+        // A leftover temp file is a snapshot that never got renamed
+        if fs::remove_file(tmp_path(path)).is_ok() {
+            tracing::warn!("Discarded an incomplete snapshot of {}", path);
+        }
+
+        let (size, data) = match pixel_file::decode(fs::read(path)?)? {
+            PixelFile::Versioned { size, data } => (size, data),
+            PixelFile::Headerless { mut data } => {
+                let backup_path = format!("{}.headerless", path);
+                tracing::warn!(
+                    "{} has no header, migrating it as a {}x{} canvas (original kept as {})",
+                    path,
+                    legacy_size.width,
+                    legacy_size.height,
+                    backup_path
+                );
+                fs::copy(path, &backup_path)?;
+
+                let expected = (legacy_size.width * legacy_size.height / 2) as usize;
+                if data.len() != expected {
+                    tracing::warn!(
+                        "{} holds {} bytes but a {}x{} canvas needs {}, adjusting it",
+                        path,
+                        data.len(),
+                        legacy_size.width,
+                        legacy_size.height,
+                        expected
+                    );
+                    data.resize(expected, 0);
+                }
+                (legacy_size.clone(), data)
+            }
+        };
+
+        let ops = Wal::read_all(&wal_path(path))?;
+        let mut canvas = CanvasBuffer {
+            data,
+            width: size.width,
+            height: size.height,
+            path: path.to_string(),
+            wal: Wal::open(&wal_path(path))?,
+            dirty: true,
+        };
+
+        if !ops.is_empty() {
+            tracing::info!(
+                "Replaying {} operations from the write-ahead log",
+                ops.len()
+            );
+        }
+        for op in ops {
+            canvas.apply(op);
+        }
+
+        canvas.snapshot()?;
+        Ok(canvas)
+    }
+
+    pub fn size(&self) -> CanvasSize {
+        CanvasSize {
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    pub fn get(&self, pixel_index: u32) -> u8 {
+        get_nibble(&self.data, pixel_index)
+    }
+
+    fn set(&mut self, pixel_index: u32, color_index: u8) {
+        set_nibble(&mut self.data, pixel_index, color_index);
+    }
+
+    fn apply(&mut self, op: WalOp) {
+        let width = self.width;
+        let pixel_count = self.data.len() as u32 * 2;
+        let in_bounds = |x: u32, y: u32| x < width && y * width + x < pixel_count;
+
+        match op {
+            WalOp::Pixel { x, y, color_index } => {
+                if in_bounds(x, y) {
+                    self.set(y * width + x, color_index);
+                }
+            }
+            WalOp::Whiten {
+                x_min,
+                y_min,
+                x_max,
+                y_max,
+            } => {
+                for y in y_min..=y_max {
+                    for x in x_min..=x_max {
+                        if in_bounds(x, y) {
+                            self.set(y * width + x, 0);
+                        }
+                    }
+                }
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Log then apply an operation
+    pub fn write(&mut self, op: WalOp) -> std::io::Result<()> {
+        self.write_all(&[op])
+    }
+
+    /// Log then apply several operations, with a single sync of the log
+    pub fn write_all(&mut self, ops: &[WalOp]) -> std::io::Result<()> {
+        self.wal.append(ops)?;
+        for &op in ops {
+            self.apply(op);
+        }
+        Ok(())
+    }
+
+    /// Swap the whole buffer for a new layout and snapshot it right away,
+    /// logged operations only make sense for the layout they were written against
+    pub fn replace(&mut self, data: Vec<u8>, size: &CanvasSize) -> std::io::Result<()> {
+        self.data = data;
+        self.width = size.width;
+        self.height = size.height;
+        self.dirty = true;
+        self.snapshot()
+    }
+
+    /// Atomically write the canvas to disk and empty the write-ahead log
+    pub fn snapshot(&mut self) -> std::io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        write_atomically(&self.path, &pixel_file::encode(&self.size(), &self.data))?;
+        self.wal.truncate()?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+pub async fn flush_canvas(state: &AppState) {
+    let mut canvas = state.canvas.lock().await;
+    if let Err(e) = canvas.snapshot() {
+        tracing::error!("Failed to snapshot canvas to {}: {}", state.file_path, e);
+    }
+}
+
+/// Periodically snapshot the in-memory canvas
+pub fn spawn_flusher(state: AppState, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            flush_canvas(&state).await;
+        }
+    });
+}
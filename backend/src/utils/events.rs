@@ -0,0 +1,103 @@
+use crate::routes::state::{AppState, CanvasEvent};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Number of past events kept around for clients resuming a stream
+const EVENT_HISTORY_SIZE: usize = 4096;
+
+#[derive(Serialize, Clone)]
+pub struct EventEnvelope {
+    pub id: u64,
+    #[serde(flatten)]
+    pub event: CanvasEvent,
+}
+
+pub struct EventLog {
+    next_id: u64,
+    recent: VecDeque<EventEnvelope>,
+}
+
+impl EventLog {
+    /// Ids start at the boot time in microseconds, so versions keep growing across restarts
+    /// and a version kept from a previous run is always too old to resume from.
+    /// Stays below 2^53, safe for JavaScript clients.
+    pub fn new() -> Self {
+        let boot = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        EventLog {
+            next_id: boot.max(1),
+            recent: VecDeque::with_capacity(EVENT_HISTORY_SIZE),
+        }
+    }
+}
+
+impl EventLog {
+    /// Id of the last published event, which doubles as the canvas version
+    pub fn current_id(&self) -> u64 {
+        self.next_id - 1
+    }
+
+    /// Events published after `last_id`, or `None` when they are no longer in memory
+    pub fn since(&self, last_id: u64) -> Option<Vec<EventEnvelope>> {
+        let oldest_id = self.recent.front().map_or(self.next_id, |e| e.id);
+        if last_id.saturating_add(1) < oldest_id || last_id >= self.next_id {
+            return None;
+        }
+
+        Some(
+            self.recent
+                .iter()
+                .filter(|e| e.id > last_id)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// Assign an id to the event, remember it and push it to every live client.
+/// Canvas writers call this while holding `state.canvas` so the id is a consistent canvas version.
+pub async fn publish(state: &AppState, event: CanvasEvent) {
+    let mut log = state.event_log.lock().await;
+
+    let envelope = EventEnvelope {
+        id: log.next_id,
+        event,
+    };
+    log.next_id += 1;
+
+    if log.recent.len() == EVENT_HISTORY_SIZE {
+        log.recent.pop_front();
+    }
+    log.recent.push_back(envelope.clone());
+
+    // Sending only fails when nobody is listening
+    let _ = state.events.send(envelope);
+}
+
+/// Subscribe to live events and collect the ones published after `last_id`.
+/// Returns `None` instead of the missed events when they are no longer in memory.
+pub async fn subscribe_since(
+    state: &AppState,
+    last_id: Option<u64>,
+) -> (
+    Option<Vec<EventEnvelope>>,
+    broadcast::Receiver<EventEnvelope>,
+) {
+    // Holding the log lock guarantees no event is both replayed and received live
+    let log = state.event_log.lock().await;
+    let receiver = state.events.subscribe();
+
+    match last_id {
+        Some(last_id) => (log.since(last_id), receiver),
+        None => (Some(Vec::new()), receiver),
+    }
+}
+
+/// Current canvas version, i.e. the id of the last published event
+pub async fn canvas_version(state: &AppState) -> u64 {
+    state.event_log.lock().await.current_id()
+}
@@ -0,0 +1,150 @@
+use crate::routes::state::AppState;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One pixel write, as stored in the placement log
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Placement {
+    pub timestamp: u64, // milliseconds since the Unix epoch
+    pub x: u32,
+    pub y: u32,
+    pub color: u8, // index in COLORS
+    pub actor: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub ip_hash: String, // salted hash of the client IP, empty for admin writes
+    /// Color the pixel had before, absent from lines logged before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<u8>,
+}
+
+/// Actor recorded for pixels written from the admin panel
+pub const ADMIN_ACTOR: &str = "admin";
+
+/// Who is placing a pixel
+pub struct Actor {
+    pub id: String, // `session:<id>` for anonymous clients, `user:<sub>` once logged in
+    pub ip_hash: String, // salted hash of the client IP
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn history_path(file_path: &str) -> String {
+    Path::new(file_path)
+        .with_file_name("placements.jsonl")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Append-only log of every placement (one JSON object per line), also kept in memory
+pub struct PlacementLog {
+    path: String,
+    file: File,
+    pub entries: Vec<Placement>,
+    /// Positions in `entries` of each pixel's placements, oldest first.
+    /// Keyed by coordinates so resizes don't shuffle it.
+    by_pixel: HashMap<(u32, u32), Vec<usize>>,
+}
+
+impl PlacementLog {
+    pub fn read(path: &str) -> std::io::Result<Vec<Placement>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(placement) => entries.push(placement),
+                // Most likely a line torn by a crash
+                Err(e) => tracing::warn!("Skipping line {} of {}: {}", number + 1, path, e),
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let entries = Self::read(path)?;
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut by_pixel: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+        for (i, placement) in entries.iter().enumerate() {
+            by_pixel
+                .entry((placement.x, placement.y))
+                .or_default()
+                .push(i);
+        }
+        Ok(PlacementLog {
+            path: path.to_string(),
+            file,
+            entries,
+            by_pixel,
+        })
+    }
+
+    /// Every placement at `(x, y)`, oldest first
+    pub fn pixel_history(&self, x: u32, y: u32) -> impl Iterator<Item = &Placement> {
+        self.by_pixel
+            .get(&(x, y))
+            .into_iter()
+            .flatten()
+            .map(|&i| &self.entries[i])
+    }
+
+    /// Most recent placement at `(x, y)`, if any
+    pub fn last_placement(&self, x: u32, y: u32) -> Option<&Placement> {
+        self.pixel_history(x, y).last()
+    }
+
+    pub fn append(&mut self, placements: &[Placement]) -> std::io::Result<()> {
+        let mut lines = Vec::new();
+        for placement in placements {
+            serde_json::to_writer(&mut lines, placement)?;
+            lines.push(b'\n');
+        }
+        self.file.write_all(&lines)?;
+        for placement in placements {
+            self.by_pixel
+                .entry((placement.x, placement.y))
+                .or_default()
+                .push(self.entries.len());
+            self.entries.push(placement.clone());
+        }
+        Ok(())
+    }
+
+    /// Move the current log to `backup_path` and start an empty one
+    pub fn rotate(&mut self, backup_path: &str) -> std::io::Result<()> {
+        // `rename` would silently replace an older archive
+        if Path::new(backup_path).exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists", backup_path),
+            ));
+        }
+        fs::rename(&self.path, backup_path)?;
+        let path = self.path.clone();
+        *self = Self::open(&path)?;
+        Ok(())
+    }
+}
+
+/// Append to the placement log. Failures are only logged, the canvas stays the source of truth
+pub async fn record_placements(state: &AppState, placements: &[Placement]) {
+    let mut history = state.history.lock().await;
+    if let Err(e) = history.append(placements) {
+        tracing::error!("Failed to record {} placements: {}", placements.len(), e);
+    }
+}
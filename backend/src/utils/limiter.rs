@@ -0,0 +1,473 @@
+use crate::config::LimitsConfig;
+use crate::routes::state::AppState;
+use crate::utils::history::now_millis;
+use axum::{
+    http::{StatusCode, header},
+    response::{IntoResponse, Json, Response},
+};
+use dashmap::{DashMap, mapref::entry::Entry, mapref::one::RefMut};
+use ipnet::IpNet;
+use serde_json::json;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Placements from one IP address since `start`, reset every cooldown
+pub struct IpWindow {
+    pub start: SystemTime,
+    pub count: u32,
+}
+
+impl IpWindow {
+    /// Whether the window is over, a clock that went backwards counting as over
+    fn expired(&self, delay: Duration, now: SystemTime) -> bool {
+        now.duration_since(self.start).map_or(true, |e| e >= delay)
+    }
+
+    /// Time before the window lets another placement through
+    fn wait(&self, ip_burst: u32, delay: Duration, now: SystemTime) -> Duration {
+        if self.count < ip_burst || self.expired(delay, now) {
+            Duration::ZERO
+        } else {
+            delay.saturating_sub(now.duration_since(self.start).unwrap_or_default())
+        }
+    }
+}
+
+/// Placement credits of one actor, one more earned every `delay` up to the bucket size
+#[derive(Clone, Copy)]
+pub struct Bucket {
+    pub credits: u32,
+    pub refilled_at: SystemTime, // start of the credit being earned
+}
+
+impl Bucket {
+    fn full(size: u32, now: SystemTime) -> Self {
+        Bucket {
+            credits: size,
+            refilled_at: now,
+        }
+    }
+
+    /// Add the credits earned since `refilled_at`
+    fn refill(&mut self, size: u32, delay: Duration, now: SystemTime) {
+        let earned = match now.duration_since(self.refilled_at) {
+            _ if delay.is_zero() => u128::MAX,
+            Ok(elapsed) => elapsed.as_nanos() / delay.as_nanos(),
+            // A clock that went backwards counts as elapsed
+            Err(_) => u128::MAX,
+        };
+        let credits = (self.credits as u128).saturating_add(earned);
+        if credits >= size as u128 {
+            *self = Bucket::full(size, now);
+        } else {
+            self.credits = credits as u32;
+            self.refilled_at += delay * earned as u32;
+        }
+    }
+
+    /// Time left before the next credit, `None` when the bucket is full
+    fn next_credit_in(&self, size: u32, delay: Duration, now: SystemTime) -> Option<Duration> {
+        if self.credits >= size {
+            return None;
+        }
+        let elapsed = now.duration_since(self.refilled_at).unwrap_or_default();
+        Some(delay.saturating_sub(elapsed))
+    }
+}
+
+/// Map keeping count of its entries, as `DashMap::len` locks every shard.
+/// Entries are only added through `get_or_insert_with` and removed through `retain`.
+struct Tracked<K, V> {
+    map: DashMap<K, V>,
+    len: AtomicUsize,
+}
+
+impl<K: Eq + Hash, V> Tracked<K, V> {
+    fn new() -> Self {
+        Tracked {
+            map: DashMap::new(),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    fn get_or_insert_with(&self, key: K, default: impl FnOnce() -> V) -> RefMut<'_, K, V> {
+        match self.map.entry(key) {
+            Entry::Occupied(entry) => entry.into_ref(),
+            Entry::Vacant(entry) => {
+                // Counted under the shard lock, so `retain` never sees an uncounted entry
+                self.len.fetch_add(1, Ordering::Relaxed);
+                entry.insert(default())
+            }
+        }
+    }
+
+    /// Keep the entries `keep` holds true for, returning how many were dropped
+    fn retain(&self, mut keep: impl FnMut(&mut V) -> bool) -> usize {
+        let mut dropped = 0;
+        self.map.retain(|_, value| {
+            let kept = keep(value);
+            dropped += !kept as usize;
+            kept
+        });
+        self.len.fetch_sub(dropped, Ordering::Relaxed);
+        dropped
+    }
+}
+
+/// Shortest time between two sweeps forced by a full limiter, each one locks every shard
+const EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Rate limiting state. Entries are sharded so placements don't contend on one lock,
+/// and dropped by the sweeper once they no longer restrict anyone.
+pub struct Limiter {
+    buckets: Tracked<String, Bucket>, // per actor id, absent means full
+    ip_windows: Tracked<IpAddr, IpWindow>, // per address, absent means unused
+    prefix_windows: Tracked<IpNet, IpWindow>, // per network prefix, absent means unused
+    ip_burst: u32,
+    prefix_burst: u32,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    max_tracked: usize, // per map, newcomers are refused beyond it
+    swept: AtomicU64,
+    refused: AtomicU64,
+    last_eviction: AtomicU64, // milliseconds since the Unix epoch
+}
+
+impl Limiter {
+    pub fn new(limits: &LimitsConfig) -> Self {
+        assert!(limits.ipv4_prefix <= 32, "ipv4_prefix must be at most 32");
+        assert!(limits.ipv6_prefix <= 128, "ipv6_prefix must be at most 128");
+        Limiter {
+            buckets: Tracked::new(),
+            ip_windows: Tracked::new(),
+            prefix_windows: Tracked::new(),
+            ip_burst: limits.ip_burst,
+            prefix_burst: limits.prefix_burst,
+            ipv4_prefix: limits.ipv4_prefix,
+            ipv6_prefix: limits.ipv6_prefix,
+            max_tracked: limits.max_tracked,
+            swept: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+            last_eviction: AtomicU64::new(0),
+        }
+    }
+
+    /// Network a client address is aggregated into, e.g. its /64 for IPv6
+    pub fn prefix_of(&self, ip: IpAddr) -> IpNet {
+        let length = match ip {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+        IpNet::new(ip, length)
+            .expect("prefix lengths are checked in Limiter::new")
+            .trunc()
+    }
+
+    /// Whether tracking a new actor, address or prefix would go over `max_tracked`
+    fn over_capacity(&self, actor: &str, ip: IpAddr, prefix: IpNet) -> bool {
+        (self.buckets.len() >= self.max_tracked && !self.buckets.map.contains_key(actor))
+            || (self.ip_windows.len() >= self.max_tracked && !self.ip_windows.map.contains_key(&ip))
+            || (self.prefix_windows.len() >= self.max_tracked
+                && !self.prefix_windows.map.contains_key(&prefix))
+    }
+
+    /// Like `over_capacity`, but first makes room by sweeping early (at most once per
+    /// `EVICTION_INTERVAL`), so idle entries give way to newcomers
+    fn is_full(
+        &self,
+        actor: &str,
+        ip: IpAddr,
+        prefix: IpNet,
+        bucket_size: u32,
+        delay: Duration,
+    ) -> bool {
+        if !self.over_capacity(actor, ip, prefix) {
+            return false;
+        }
+        let now = now_millis();
+        let last = self.last_eviction.load(Ordering::Relaxed);
+        if now.saturating_sub(last) >= EVICTION_INTERVAL.as_millis() as u64
+            && self
+                .last_eviction
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            let swept = self.sweep(bucket_size, delay);
+            tracing::debug!("Limiter at capacity, swept {} entries early", swept);
+        }
+        self.over_capacity(actor, ip, prefix)
+    }
+
+    /// Drop the entries that would behave like absent ones
+    fn sweep(&self, bucket_size: u32, delay: Duration) -> usize {
+        let now = SystemTime::now();
+        let keep_window = |window: &mut IpWindow| !window.expired(delay, now);
+        let swept = self.buckets.retain(|bucket| {
+            let mut bucket = *bucket;
+            bucket.refill(bucket_size, delay, now);
+            bucket.credits < bucket_size
+        }) + self.ip_windows.retain(keep_window)
+            + self.prefix_windows.retain(keep_window);
+        self.swept.fetch_add(swept as u64, Ordering::Relaxed);
+        swept
+    }
+
+    pub fn metrics(&self) -> serde_json::Value {
+        json!({
+            "trackedActors": self.buckets.len(),
+            "trackedIps": self.ip_windows.len(),
+            "trackedPrefixes": self.prefix_windows.len(),
+            "maxTracked": self.max_tracked,
+            "swept": self.swept.load(Ordering::Relaxed),
+            "refusedAtCapacity": self.refused.load(Ordering::Relaxed),
+        })
+    }
+}
+
+async fn current_limits(state: &AppState) -> (u32, Duration) {
+    let bucket_size = *state.bucket_size.lock().await;
+    let delay = Duration::from_secs(*state.delay.lock().await as u64);
+    (bucket_size, delay)
+}
+
+/// Periodically drop rate limiting entries that have gone back to their initial state,
+/// used proof-of-work challenges that expired and old admin login attempts
+pub fn spawn_sweeper(state: AppState, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let (bucket_size, delay) = current_limits(&state).await;
+            let swept = state.limiter.sweep(bucket_size, delay)
+                + state.pow.sweep(now_millis())
+                + state.login_guard.sweep(SystemTime::now())
+                + state.admin_sessions.sweep(now_millis());
+            if swept > 0 {
+                tracing::debug!("Swept {} rate limiting entries", swept);
+            }
+        }
+    });
+}
+
+/// Credits of an actor, without spending any
+pub struct CreditStatus {
+    pub credits: u32,
+    pub bucket_size: u32,
+    pub next_credit_in: Option<Duration>,
+}
+
+pub async fn credit_status(actor: &str, state: &AppState) -> CreditStatus {
+    let (bucket_size, delay) = current_limits(state).await;
+
+    let now = SystemTime::now();
+    let mut bucket = state
+        .limiter
+        .buckets
+        .map
+        .get(actor)
+        .map(|bucket| *bucket)
+        .unwrap_or_else(|| Bucket::full(bucket_size, now));
+    bucket.refill(bucket_size, delay, now);
+
+    CreditStatus {
+        credits: bucket.credits,
+        bucket_size,
+        next_credit_in: bucket.next_credit_in(bucket_size, delay, now),
+    }
+}
+
+/// Time before `actor` behind `ip` may place a pixel, zero when it can right now
+pub async fn remaining_cooldown(actor: &str, ip: IpAddr, state: &AppState) -> Duration {
+    let status = credit_status(actor, state).await;
+    let (_, delay) = current_limits(state).await;
+    let limiter = &state.limiter;
+    let now = SystemTime::now();
+
+    let bucket_wait = match status.credits {
+        0 => status.next_credit_in.unwrap_or_default(),
+        _ => Duration::ZERO,
+    };
+    let ip_wait = limiter
+        .ip_windows
+        .map
+        .get(&ip)
+        .map_or(Duration::ZERO, |w| w.wait(limiter.ip_burst, delay, now));
+    let prefix_wait = limiter
+        .prefix_windows
+        .map
+        .get(&limiter.prefix_of(ip))
+        .map_or(Duration::ZERO, |w| w.wait(limiter.prefix_burst, delay, now));
+    bucket_wait.max(ip_wait).max(prefix_wait)
+}
+
+/// JSON description of a cooldown, shared by 429 responses and `GET /api/cooldown`
+pub fn cooldown_json(wait: Duration) -> serde_json::Value {
+    let remaining = wait.as_millis() as u64;
+    json!({
+        "remaining": remaining, // milliseconds
+        "nextAllowedAt": now_millis() + remaining, // milliseconds since the Unix epoch
+    })
+}
+
+/// 429 telling the client exactly how long to wait
+pub fn too_many_requests(wait: Duration, message: &str) -> Response {
+    let mut body = cooldown_json(wait);
+    body["error"] = json!(message);
+    // Retry-After only has second precision, round up so retrying then succeeds
+    let retry_after = wait.as_millis().div_ceil(1000).max(1);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        Json(body),
+    )
+        .into_response()
+}
+
+/// Current window of `key`, restarted if `delay` went by
+fn current_window<K: Eq + Hash>(
+    windows: &Tracked<K, IpWindow>,
+    key: K,
+    delay: Duration,
+    now: SystemTime,
+) -> RefMut<'_, K, IpWindow> {
+    let mut window = windows.get_or_insert_with(key, || IpWindow {
+        start: now,
+        count: 0,
+    });
+    if window.expired(delay, now) {
+        window.start = now;
+        window.count = 0;
+    }
+    window
+}
+
+/// Each actor (session or user) spends credits from its own bucket. Addresses and their
+/// prefix (so rotating through an IPv6 /64 doesn't help) only cap how many pixels the actors
+/// behind them place per `delay`, so a shared NAT isn't throttled but clearing cookies
+/// doesn't help either. On refusal, returns how long to wait.
+pub async fn is_request_allowed(actor: &str, ip: IpAddr, state: &AppState) -> Result<(), Duration> {
+    let (bucket_size, delay) = current_limits(state).await;
+    let limiter = &state.limiter;
+    let prefix = limiter.prefix_of(ip);
+
+    // Checked before taking any entry, so a refused newcomer takes no room
+    if limiter.is_full(actor, ip, prefix, bucket_size, delay) {
+        limiter.refused.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(
+            "Rate limiter is tracking {} entries, refusing newcomers",
+            limiter.max_tracked
+        );
+        return Err(delay);
+    }
+
+    // Addresses first, so a refused request doesn't leave a bucket behind for its actor
+    let now = SystemTime::now();
+    let mut ip_window = current_window(&limiter.ip_windows, ip, delay, now);
+    let mut prefix_window = current_window(&limiter.prefix_windows, prefix, delay, now);
+    let wait = ip_window
+        .wait(limiter.ip_burst, delay, now)
+        .max(prefix_window.wait(limiter.prefix_burst, delay, now));
+    if !wait.is_zero() {
+        let bucket_wait = limiter.buckets.map.get(actor).and_then(|bucket| {
+            let mut bucket = *bucket;
+            bucket.refill(bucket_size, delay, now);
+            (bucket.credits == 0)
+                .then(|| bucket.next_credit_in(bucket_size, delay, now))
+                .flatten()
+        });
+        return Err(wait.max(bucket_wait.unwrap_or_default()));
+    }
+
+    let mut bucket = limiter
+        .buckets
+        .get_or_insert_with(actor.to_string(), || Bucket::full(bucket_size, now));
+    bucket.refill(bucket_size, delay, now);
+    if bucket.credits == 0 {
+        return Err(bucket
+            .next_credit_in(bucket_size, delay, now)
+            .unwrap_or_default());
+    }
+
+    ip_window.count += 1;
+    prefix_window.count += 1;
+    bucket.credits -= 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn buckets_refill_one_credit_per_delay() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut bucket = Bucket {
+            credits: 0,
+            refilled_at: start,
+        };
+
+        bucket.refill(3, DELAY, start + Duration::from_secs(25));
+        assert_eq!(bucket.credits, 2);
+        // The credit being earned keeps its progress
+        assert_eq!(bucket.refilled_at, start + Duration::from_secs(20));
+        assert_eq!(
+            bucket.next_credit_in(3, DELAY, start + Duration::from_secs(25)),
+            Some(Duration::from_secs(5))
+        );
+
+        bucket.refill(3, DELAY, start + Duration::from_secs(100));
+        assert_eq!(bucket.credits, 3);
+        assert_eq!(
+            bucket.next_credit_in(3, DELAY, start + Duration::from_secs(100)),
+            None
+        );
+    }
+
+    #[test]
+    fn buckets_refill_when_the_clock_goes_back_or_without_delay() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut bucket = Bucket {
+            credits: 0,
+            refilled_at: start,
+        };
+        bucket.refill(2, DELAY, start - Duration::from_secs(1));
+        assert_eq!(bucket.credits, 2);
+
+        let mut bucket = Bucket {
+            credits: 0,
+            refilled_at: start,
+        };
+        bucket.refill(2, Duration::ZERO, start);
+        assert_eq!(bucket.credits, 2);
+    }
+
+    #[test]
+    fn ip_windows_allow_a_burst_per_delay() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let window = IpWindow { start, count: 2 };
+        let now = start + Duration::from_secs(4);
+        assert_eq!(window.wait(3, DELAY, now), Duration::ZERO);
+        assert_eq!(window.wait(2, DELAY, now), Duration::from_secs(6));
+        assert_eq!(window.wait(2, DELAY, start + DELAY), Duration::ZERO);
+    }
+
+    #[test]
+    fn tracked_maps_count_their_entries() {
+        let tracked = Tracked::new();
+        for key in [1, 2, 3, 2] {
+            *tracked.get_or_insert_with(key, || 0) += 1;
+        }
+        assert_eq!(tracked.len(), 3);
+        assert_eq!(tracked.retain(|&mut count| count > 1), 2);
+        assert_eq!(tracked.len(), 1);
+        assert_eq!(tracked.map.len(), 1);
+    }
+}
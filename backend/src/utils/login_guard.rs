@@ -0,0 +1,129 @@
+use dashmap::DashMap;
+use ipnet::IpNet;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+/// Attempts in a row from one prefix before backing off
+const FREE_ATTEMPTS: u32 = 3;
+/// First backoff, doubled with each further attempt
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest backoff, reached after 13 attempts in a row. Attempts older than this are forgotten.
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Attempts from everywhere within `GLOBAL_WINDOW` that lock the login for everyone
+const GLOBAL_MAX_ATTEMPTS: u32 = 100;
+const GLOBAL_WINDOW: Duration = Duration::from_secs(60);
+const GLOBAL_LOCKOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy)]
+/// Attempts since the last successful login
+struct Attempts {
+    count: u32,
+    last: SystemTime,
+}
+
+impl Attempts {
+    fn stale(&self, now: SystemTime) -> bool {
+        now.duration_since(self.last).is_ok_and(|e| e >= LOCKOUT)
+    }
+
+    /// When the next attempt is allowed
+    fn blocked_until(&self) -> SystemTime {
+        let Some(excess) = self.count.checked_sub(FREE_ATTEMPTS) else {
+            return self.last;
+        };
+        let backoff = BASE_BACKOFF
+            .checked_mul(1 << excess.min(20))
+            .map_or(LOCKOUT, |b| b.min(LOCKOUT));
+        self.last + backoff
+    }
+}
+
+struct GlobalAttempts {
+    window_start: SystemTime,
+    count: u32,
+    locked_until: Option<SystemTime>,
+}
+
+/// Throttles admin login attempts per network prefix and globally,
+/// so password spraying is slow and doesn't burn bcrypt time
+pub struct LoginGuard {
+    by_prefix: DashMap<IpNet, Attempts>,
+    global: Mutex<GlobalAttempts>,
+}
+
+fn remaining(until: SystemTime, now: SystemTime) -> Option<Duration> {
+    until.duration_since(now).ok().filter(|d| !d.is_zero())
+}
+
+impl Default for LoginGuard {
+    fn default() -> Self {
+        LoginGuard {
+            by_prefix: DashMap::new(),
+            global: Mutex::new(GlobalAttempts {
+                window_start: SystemTime::now(),
+                count: 0,
+                locked_until: None,
+            }),
+        }
+    }
+}
+
+impl LoginGuard {
+    /// Count an attempt from `prefix` before checking the password, so parallel attempts
+    /// can't all slip through. Returns the number of attempts since the last success,
+    /// or how long to wait when locked out.
+    pub async fn begin_attempt(&self, prefix: IpNet, now: SystemTime) -> Result<u32, Duration> {
+        let mut global = self.global.lock().await;
+        if let Some(wait) = global.locked_until.and_then(|until| remaining(until, now)) {
+            return Err(wait);
+        }
+
+        let mut attempts = self.by_prefix.entry(prefix).or_insert(Attempts {
+            count: 0,
+            last: now,
+        });
+        if attempts.stale(now) {
+            attempts.count = 0;
+        }
+        if let Some(wait) = remaining(attempts.blocked_until(), now) {
+            return Err(wait);
+        }
+        attempts.count += 1;
+        attempts.last = now;
+
+        if now
+            .duration_since(global.window_start)
+            .map_or(true, |e| e >= GLOBAL_WINDOW)
+        {
+            global.window_start = now;
+            global.count = 0;
+        }
+        global.count += 1;
+        if global.count >= GLOBAL_MAX_ATTEMPTS {
+            tracing::warn!(
+                "{} admin login attempts within {:?}, locking the login for {:?}",
+                global.count,
+                GLOBAL_WINDOW,
+                GLOBAL_LOCKOUT
+            );
+            global.locked_until = Some(now + GLOBAL_LOCKOUT);
+            global.count = 0;
+        }
+        Ok(attempts.count)
+    }
+
+    /// The password was right, forget the attempts of `prefix` and take this one
+    /// back from the global count, which is only meant for failures
+    pub async fn record_success(&self, prefix: IpNet) {
+        let mut global = self.global.lock().await;
+        global.count = global.count.saturating_sub(1);
+        self.by_prefix.remove(&prefix);
+    }
+
+    /// Forget prefixes whose attempts are too old to matter
+    pub fn sweep(&self, now: SystemTime) -> usize {
+        let before = self.by_prefix.len();
+        self.by_prefix.retain(|_, attempts| !attempts.stale(now));
+        before.saturating_sub(self.by_prefix.len())
+    }
+}
@@ -1 +1,16 @@
+pub mod admin_sessions;
+pub mod bans;
+pub mod canvas;
+pub mod events;
+pub mod history;
+pub mod limiter;
+pub mod login_guard;
+pub mod oidc;
+pub mod pixel_file;
+pub mod pow;
+pub mod render;
 pub mod requests;
+pub mod session;
+pub mod settings;
+pub mod timelapse;
+pub mod wal;
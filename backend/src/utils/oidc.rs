@@ -0,0 +1,372 @@
+use crate::config::OidcConfig;
+use crate::utils::session::{User, random_token};
+use base64::engine::{Engine, general_purpose::URL_SAFE_NO_PAD};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+
+/// The parts of `/.well-known/openid-configuration` the login flow needs
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct UserInfoResponse {
+    sub: String,
+    name: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+}
+
+/// Login in progress, kept in a private cookie from the redirect to the callback
+#[derive(Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub state: String,    // echoed back by the provider, guards against CSRF
+    pub verifier: String, // PKCE code verifier
+}
+
+/// Authorization code flow against `OidcConfig::issuer`.
+/// Discovery is done on the first login, so the provider may start after the server.
+pub struct OidcClient {
+    config: OidcConfig,
+    http: reqwest::Client,
+    metadata: OnceCell<ProviderMetadata>,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Self {
+        OidcClient {
+            config,
+            http: reqwest::Client::new(),
+            metadata: OnceCell::new(),
+        }
+    }
+
+    pub fn require_login(&self) -> bool {
+        self.config.require_login
+    }
+
+    async fn metadata(&self) -> Result<&ProviderMetadata, String> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let issuer = self.config.issuer.trim_end_matches('/');
+                let url = format!("{}/.well-known/openid-configuration", issuer);
+                let metadata: ProviderMetadata = self
+                    .http
+                    .get(&url)
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| format!("Discovery failed: {}", e))?
+                    .json()
+                    .await
+                    .map_err(|e| format!("Invalid discovery document: {}", e))?;
+
+                if metadata.issuer.trim_end_matches('/') != issuer {
+                    return Err(format!(
+                        "Discovery document is for issuer {}, expected {}",
+                        metadata.issuer, issuer
+                    ));
+                }
+                Ok(metadata)
+            })
+            .await
+    }
+
+    /// Where to send the browser, and what to remember until it comes back
+    pub async fn authorization_url(&self) -> Result<(String, PendingLogin), String> {
+        let metadata = self.metadata().await?;
+        let pending = PendingLogin {
+            state: random_token(),
+            verifier: format!("{}{}", random_token(), random_token()),
+        };
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(pending.verifier.as_bytes()));
+
+        let url = Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_url),
+                ("scope", &self.config.scopes.join(" ")),
+                ("state", &pending.state),
+                ("code_challenge", &challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| format!("Invalid authorization endpoint: {}", e))?;
+
+        Ok((url.into(), pending))
+    }
+
+    /// Exchange the authorization code and fetch who logged in
+    pub async fn authenticate(&self, code: &str, pending: &PendingLogin) -> Result<User, String> {
+        let metadata = self.metadata().await?;
+
+        let token: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_url),
+                ("code_verifier", &pending.verifier),
+            ])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("Token exchange failed: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid token response: {}", e))?;
+
+        let info: UserInfoResponse = self
+            .http
+            .get(&metadata.userinfo_endpoint)
+            .bearer_auth(&token.access_token)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("Userinfo request failed: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid userinfo response: {}", e))?;
+
+        let name = info
+            .name
+            .or(info.preferred_username)
+            .or(info.email)
+            .unwrap_or_else(|| info.sub.clone());
+        Ok(User { id: info.sub, name })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Form, Json, Router,
+        extract::{Query, State},
+        http::{HeaderMap, StatusCode, header},
+        response::{IntoResponse, Redirect, Response},
+        routing::{get, post},
+    };
+    use base64::engine::general_purpose::STANDARD;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    const CLIENT_ID: &str = "pixel-war";
+    const CLIENT_SECRET: &str = "hunter2";
+    const REDIRECT_URL: &str = "http://pixel.test/api/login/callback";
+
+    /// Local stand-in for the SSO: discovery, authorization with PKCE, token and userinfo
+    struct Provider {
+        issuer: String,
+        advertised_issuer: String,
+        codes: Mutex<HashMap<String, String>>, // authorization code -> PKCE challenge
+    }
+
+    async fn discovery(State(provider): State<Arc<Provider>>) -> Json<serde_json::Value> {
+        Json(json!({
+            "issuer": provider.advertised_issuer,
+            "authorization_endpoint": format!("{}/authorize", provider.issuer),
+            "token_endpoint": format!("{}/token", provider.issuer),
+            "userinfo_endpoint": format!("{}/userinfo", provider.issuer),
+        }))
+    }
+
+    async fn authorize(
+        State(provider): State<Arc<Provider>>,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> Response {
+        let param = |name: &str| params.get(name).map(String::as_str);
+        if param("response_type") != Some("code")
+            || param("client_id") != Some(CLIENT_ID)
+            || param("redirect_uri") != Some(REDIRECT_URL)
+            || param("code_challenge_method") != Some("S256")
+            || !param("scope").is_some_and(|scope| scope.split(' ').any(|s| s == "openid"))
+        {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        let (Some(state), Some(challenge)) = (param("state"), param("code_challenge")) else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+
+        let code = random_token();
+        provider
+            .codes
+            .lock()
+            .unwrap()
+            .insert(code.clone(), challenge.to_string());
+        let callback =
+            Url::parse_with_params(REDIRECT_URL, &[("code", code.as_str()), ("state", state)])
+                .unwrap();
+        Redirect::to(callback.as_str()).into_response()
+    }
+
+    async fn token(
+        State(provider): State<Arc<Provider>>,
+        headers: HeaderMap,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Response {
+        let credentials = format!("{}:{}", CLIENT_ID, CLIENT_SECRET);
+        let expected = format!("Basic {}", STANDARD.encode(credentials));
+        if headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            != Some(&expected)
+        {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        let field = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+        if field("grant_type") != "authorization_code" || field("redirect_uri") != REDIRECT_URL {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+
+        // Codes are single use, whatever the outcome
+        let Some(challenge) = provider.codes.lock().unwrap().remove(field("code")) else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        if URL_SAFE_NO_PAD.encode(Sha256::digest(field("code_verifier").as_bytes())) != challenge {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        Json(json!({ "access_token": "access-token", "token_type": "Bearer" })).into_response()
+    }
+
+    async fn userinfo(headers: HeaderMap) -> Response {
+        if headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            != Some("Bearer access-token")
+        {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        Json(json!({ "sub": "f3a1-alice", "preferred_username": "alice" })).into_response()
+    }
+
+    /// Start a provider on a free port, returns its issuer URL
+    async fn spawn_provider(advertised_issuer: Option<&str>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let provider = Arc::new(Provider {
+            issuer: issuer.clone(),
+            advertised_issuer: advertised_issuer.unwrap_or(&issuer).to_string(),
+            codes: Mutex::new(HashMap::new()),
+        });
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/authorize", get(authorize))
+            .route("/token", post(token))
+            .route("/userinfo", get(userinfo))
+            .with_state(provider);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        issuer
+    }
+
+    fn client(issuer: &str) -> OidcClient {
+        OidcClient::new(OidcConfig {
+            issuer: format!("{}/", issuer),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: CLIENT_SECRET.to_string(),
+            redirect_url: REDIRECT_URL.to_string(),
+            scopes: vec!["openid".to_string(), "profile".to_string()],
+            require_login: false,
+        })
+    }
+
+    /// Play the browser: follow the authorization URL and return the callback parameters
+    async fn authorize_in_browser(url: &str) -> HashMap<String, String> {
+        let browser = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let response = browser.get(url).send().await.unwrap();
+        assert!(response.status().is_redirection(), "{}", response.status());
+        let location = response.headers()[header::LOCATION].to_str().unwrap();
+        let callback = Url::parse(location).unwrap();
+        assert!(callback.as_str().starts_with(REDIRECT_URL));
+        callback.query_pairs().into_owned().collect()
+    }
+
+    #[tokio::test]
+    async fn logs_in_through_the_provider() {
+        let issuer = spawn_provider(None).await;
+        let client = client(&issuer);
+
+        let (url, pending) = client.authorization_url().await.unwrap();
+        assert!(url.starts_with(&format!("{}/authorize?", issuer)));
+        let callback = authorize_in_browser(&url).await;
+        assert_eq!(callback["state"], pending.state);
+
+        let user = client
+            .authenticate(&callback["code"], &pending)
+            .await
+            .unwrap();
+        assert_eq!(user.id, "f3a1-alice");
+        assert_eq!(user.name, "alice");
+
+        // The provider refuses a replayed code
+        assert!(
+            client
+                .authenticate(&callback["code"], &pending)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn each_login_gets_its_own_state_and_verifier() {
+        let issuer = spawn_provider(None).await;
+        let client = client(&issuer);
+
+        let (_, first) = client.authorization_url().await.unwrap();
+        let (url, second) = client.authorization_url().await.unwrap();
+        assert_ne!(first.state, second.state);
+        assert_ne!(first.verifier, second.verifier);
+
+        // The verifier of another login doesn't match this code's challenge
+        let callback = authorize_in_browser(&url).await;
+        let Err(error) = client.authenticate(&callback["code"], &first).await else {
+            panic!("logged in with the wrong verifier");
+        };
+        assert!(error.starts_with("Token exchange failed"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_discovery_document_for_another_issuer() {
+        let issuer = spawn_provider(Some("https://sso.example.org")).await;
+        let Err(error) = client(&issuer).authorization_url().await else {
+            panic!("trusted a discovery document for another issuer");
+        };
+        assert!(
+            error.starts_with("Discovery document is for issuer"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_an_unreachable_provider() {
+        // Bind then drop, so nothing listens on the port
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let Err(error) = client(&issuer).authorization_url().await else {
+            panic!("discovery succeeded without a provider");
+        };
+        assert!(error.starts_with("Discovery failed"), "{}", error);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::routes::state::{CanvasSize, PALETTE_ID};
+use std::io::{Error, ErrorKind};
+
+/// `pixels.bin` layout, all integers little-endian:
+///
+/// | offset | size | field                            |
+/// |--------|------|----------------------------------|
+/// | 0      | 4    | magic `PXWR`                     |
+/// | 4      | 1    | format version                   |
+/// | 5      | 1    | palette id                       |
+/// | 6      | 2    | reserved, zero                   |
+/// | 8      | 4    | width                            |
+/// | 12     | 4    | height                           |
+/// | 16     | 4    | CRC32 of the pixel data          |
+/// | 20     | ..   | pixel data, two pixels per byte  |
+pub const MAGIC: &[u8; 4] = b"PXWR";
+pub const FORMAT_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 20;
+
+/// Content of a `pixels.bin` file
+pub enum PixelFile {
+    Versioned {
+        size: CanvasSize,
+        data: Vec<u8>,
+    },
+    /// Raw nibbles written before the header existed
+    Headerless {
+        data: Vec<u8>,
+    },
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+pub fn encode(size: &CanvasSize, data: &[u8]) -> Vec<u8> {
+    let mut file = Vec::with_capacity(HEADER_SIZE + data.len());
+    file.extend_from_slice(MAGIC);
+    file.push(FORMAT_VERSION);
+    file.push(PALETTE_ID);
+    file.extend_from_slice(&[0, 0]);
+    file.extend_from_slice(&size.width.to_le_bytes());
+    file.extend_from_slice(&size.height.to_le_bytes());
+    file.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+    file.extend_from_slice(data);
+    file
+}
+
+pub fn decode(file: Vec<u8>) -> std::io::Result<PixelFile> {
+    if file.len() < HEADER_SIZE || &file[0..4] != MAGIC {
+        return Ok(PixelFile::Headerless { data: file });
+    }
+
+    let field = |offset: usize| u32::from_le_bytes(file[offset..offset + 4].try_into().unwrap());
+
+    if file[4] != FORMAT_VERSION {
+        return Err(invalid(format!(
+            "Unsupported pixel file format version {}",
+            file[4]
+        )));
+    }
+    if file[5] != PALETTE_ID {
+        return Err(invalid(format!(
+            "Pixel file uses palette {} but the server knows palette {}",
+            file[5], PALETTE_ID
+        )));
+    }
+
+    let size = CanvasSize {
+        width: field(8),
+        height: field(12),
+    };
+    let crc = field(16);
+    let data = file[HEADER_SIZE..].to_vec();
+
+    let expected = size.width as u64 * size.height as u64 / 2;
+    if data.len() as u64 != expected {
+        return Err(invalid(format!(
+            "Pixel file declares a {}x{} canvas ({} bytes) but holds {} bytes",
+            size.width,
+            size.height,
+            expected,
+            data.len()
+        )));
+    }
+    if crc32fast::hash(&data) != crc {
+        return Err(invalid("Pixel file checksum mismatch".to_string()));
+    }
+
+    Ok(PixelFile::Versioned { size, data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> (CanvasSize, Vec<u8>) {
+        let size = CanvasSize {
+            width: 4,
+            height: 2,
+        };
+        (size, vec![0x01, 0x23, 0x45, 0x67])
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let (size, data) = sample();
+        let file = encode(&size, &data);
+        assert_eq!(file.len(), HEADER_SIZE + data.len());
+        match decode(file).unwrap() {
+            PixelFile::Versioned {
+                size: decoded,
+                data: decoded_data,
+            } => {
+                assert_eq!((decoded.width, decoded.height), (4, 2));
+                assert_eq!(decoded_data, data);
+            }
+            PixelFile::Headerless { .. } => panic!("header not recognized"),
+        }
+    }
+
+    #[test]
+    fn files_without_magic_are_headerless() {
+        let data = vec![0x11; 32];
+        assert!(matches!(
+            decode(data.clone()).unwrap(),
+            PixelFile::Headerless { data: raw } if raw == data
+        ));
+        assert!(matches!(
+            decode(b"PXWR".to_vec()).unwrap(),
+            PixelFile::Headerless { .. }
+        ));
+    }
+
+    #[test]
+    fn corrupted_files_are_rejected() {
+        let (size, data) = sample();
+
+        let mut flipped = encode(&size, &data);
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(decode(flipped).is_err());
+
+        let mut truncated = encode(&size, &data);
+        truncated.pop();
+        assert!(decode(truncated).is_err());
+
+        let mut future = encode(&size, &data);
+        future[4] = FORMAT_VERSION + 1;
+        assert!(decode(future).is_err());
+
+        let mut palette = encode(&size, &data);
+        palette[5] = PALETTE_ID.wrapping_add(1);
+        assert!(decode(palette).is_err());
+    }
+}
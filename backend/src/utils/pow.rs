@@ -0,0 +1,199 @@
+use crate::utils::session::random_token;
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// Hardest accepted difficulty, about 4 billion hashes on average
+pub const MAX_DIFFICULTY: u8 = 32;
+/// How long a challenge can be solved and submitted, in milliseconds
+pub const CHALLENGE_TTL: u64 = 5 * 60 * 1000;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Proof-of-work gate in front of placements.
+///
+/// A challenge is `<expiry>.<difficulty>.<random>.<signature>`, the HMAC binding it to the
+/// session it was issued to. The client must find a `nonce` such that
+/// `sha256("<challenge>:<nonce>")` starts with `difficulty` zero bits.
+/// Challenges are stateless until used, then remembered until they expire to refuse replays.
+pub struct ProofOfWork {
+    secret: Vec<u8>,
+    used: DashMap<String, u64>, // challenge -> expiry
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+impl ProofOfWork {
+    pub fn new(secret: &[u8]) -> Self {
+        ProofOfWork {
+            secret: secret.to_vec(),
+            used: DashMap::new(),
+        }
+    }
+
+    fn mac(&self, session: &str, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC takes any key size");
+        mac.update(session.as_bytes());
+        mac.update(b"\0");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    pub fn issue(&self, session: &str, difficulty: u8, now: u64) -> String {
+        let payload = format!("{}.{}.{}", now + CHALLENGE_TTL, difficulty, random_token());
+        let signature = self.mac(session, &payload).finalize().into_bytes();
+        let signature: String = signature.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Check a solved challenge without using it up, returns its expiry for `burn`
+    pub fn verify(
+        &self,
+        session: &str,
+        challenge: &str,
+        nonce: u64,
+        min_difficulty: u8,
+        now: u64,
+    ) -> Result<u64, &'static str> {
+        let invalid = "Invalid challenge";
+        let (payload, signature) = challenge.rsplit_once('.').ok_or(invalid)?;
+        let signature = (0..signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(signature.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or(invalid)?;
+        self.mac(session, payload)
+            .verify_slice(&signature)
+            .map_err(|_| invalid)?;
+
+        let mut fields = payload.split('.');
+        let expiry: u64 = fields.next().and_then(|f| f.parse().ok()).ok_or(invalid)?;
+        let difficulty: u8 = fields.next().and_then(|f| f.parse().ok()).ok_or(invalid)?;
+        if expiry <= now {
+            return Err("Challenge expired");
+        }
+        // Raised by an admin since it was issued
+        if difficulty < min_difficulty {
+            return Err("Challenge too easy");
+        }
+
+        let hash = Sha256::digest(format!("{}:{}", challenge, nonce).as_bytes());
+        if leading_zero_bits(&hash) < difficulty as u32 {
+            return Err("Invalid proof of work");
+        }
+
+        if self.used.contains_key(challenge) {
+            return Err("Challenge already used");
+        }
+        Ok(expiry)
+    }
+
+    /// Use up a verified challenge once the placement it pays for is accepted.
+    /// Fails when a concurrent request burned it first.
+    pub fn burn(&self, challenge: &str, expiry: u64) -> Result<(), &'static str> {
+        if self.used.insert(challenge.to_string(), expiry).is_some() {
+            return Err("Challenge already used");
+        }
+        Ok(())
+    }
+
+    /// Forget used challenges that expired, they can't be replayed anymore
+    pub fn sweep(&self, now: u64) -> usize {
+        let before = self.used.len();
+        self.used.retain(|_, expiry| *expiry > now);
+        before.saturating_sub(self.used.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn solve(challenge: &str, difficulty: u8) -> u64 {
+        (0..)
+            .find(|nonce| {
+                let hash = Sha256::digest(format!("{}:{}", challenge, nonce).as_bytes());
+                leading_zero_bits(&hash) >= difficulty as u32
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn solved_challenges_are_accepted_once() {
+        let pow = ProofOfWork::new(b"secret");
+        let challenge = pow.issue("session", 8, NOW);
+        let nonce = solve(&challenge, 8);
+
+        let expiry = pow.verify("session", &challenge, nonce, 8, NOW).unwrap();
+        assert_eq!(expiry, NOW + CHALLENGE_TTL);
+        // Verifying alone doesn't use it up
+        assert!(pow.verify("session", &challenge, nonce, 8, NOW).is_ok());
+
+        pow.burn(&challenge, expiry).unwrap();
+        assert_eq!(
+            pow.verify("session", &challenge, nonce, 8, NOW),
+            Err("Challenge already used")
+        );
+        assert!(pow.burn(&challenge, expiry).is_err());
+
+        assert_eq!(pow.sweep(expiry), 1);
+    }
+
+    #[test]
+    fn wrong_proofs_are_refused() {
+        let pow = ProofOfWork::new(b"secret");
+        let challenge = pow.issue("session", 8, NOW);
+        let nonce = solve(&challenge, 8);
+
+        assert_eq!(
+            pow.verify("other session", &challenge, nonce, 8, NOW),
+            Err("Invalid challenge")
+        );
+        assert_eq!(
+            ProofOfWork::new(b"other secret").verify("session", &challenge, nonce, 8, NOW),
+            Err("Invalid challenge")
+        );
+        let tampered = challenge.replacen(".8.", ".0.", 1);
+        assert_eq!(
+            pow.verify("session", &tampered, nonce, 0, NOW),
+            Err("Invalid challenge")
+        );
+        assert_eq!(
+            pow.verify("session", &challenge, nonce, 9, NOW),
+            Err("Challenge too easy")
+        );
+        assert_eq!(
+            pow.verify("session", &challenge, nonce, 8, NOW + CHALLENGE_TTL),
+            Err("Challenge expired")
+        );
+
+        let wrong_nonce = (0..)
+            .find(|n| {
+                let hash = Sha256::digest(format!("{}:{}", challenge, n).as_bytes());
+                leading_zero_bits(&hash) < 8
+            })
+            .unwrap();
+        assert_eq!(
+            pow.verify("session", &challenge, wrong_nonce, 8, NOW),
+            Err("Invalid proof of work")
+        );
+    }
+}
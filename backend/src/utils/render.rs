@@ -0,0 +1,96 @@
+use crate::routes::state::{COLORS, CanvasSize};
+use crate::utils::canvas::get_nibble;
+
+/// Largest upscaling of rendered images
+pub const MAX_SCALE: u32 = 32;
+/// Largest rendered image or timelapse frame, in output pixels (a byte each before encoding)
+pub const MAX_IMAGE_PIXELS: u64 = 64 * 1024 * 1024;
+
+/// Inclusive rectangle of canvas pixels
+#[derive(Clone, Copy)]
+pub struct Region {
+    pub x_start: u32,
+    pub y_start: u32,
+    pub x_end: u32,
+    pub y_end: u32,
+}
+
+impl Region {
+    pub fn full(size: &CanvasSize) -> Self {
+        Region {
+            x_start: 0,
+            y_start: 0,
+            x_end: size.width - 1,
+            y_end: size.height - 1,
+        }
+    }
+
+    pub fn fits(&self, size: &CanvasSize) -> bool {
+        self.x_start <= self.x_end
+            && self.y_start <= self.y_end
+            && self.x_end < size.width
+            && self.y_end < size.height
+    }
+
+    pub fn width(&self) -> u32 {
+        self.x_end - self.x_start + 1
+    }
+
+    pub fn height(&self) -> u32 {
+        self.y_end - self.y_start + 1
+    }
+
+    /// Width and height once rendered at `scale`, `None` beyond `MAX_IMAGE_PIXELS`
+    pub fn output_size(&self, scale: u32) -> Option<(u32, u32)> {
+        let width = self.width() as u64 * scale as u64;
+        let height = self.height() as u64 * scale as u64;
+        width
+            .checked_mul(height)
+            .filter(|&pixels| pixels <= MAX_IMAGE_PIXELS)
+            .map(|_| (width as u32, height as u32))
+    }
+}
+
+/// `COLORS` as RGB triples, in palette order
+pub fn palette_rgb() -> Vec<u8> {
+    COLORS
+        .iter()
+        .flat_map(|color| {
+            let hex = u32::from_str_radix(&color[1..], 16).unwrap_or(0);
+            [(hex >> 16) as u8, (hex >> 8) as u8, hex as u8]
+        })
+        .collect()
+}
+
+/// Expand packed nibbles into one palette index per output pixel,
+/// each canvas pixel becoming a `scale`x`scale` square
+pub fn render_indexed(data: &[u8], canvas_width: u32, region: &Region, scale: u32) -> Vec<u8> {
+    let out_width = (region.width() * scale) as usize;
+    let mut image = Vec::with_capacity(out_width * (region.height() * scale) as usize);
+
+    for y in region.y_start..=region.y_end {
+        let row_start = image.len();
+        for x in region.x_start..=region.x_end {
+            let nibble = get_nibble(data, y * canvas_width + x);
+            image.extend(std::iter::repeat_n(nibble, scale as usize));
+        }
+        for _ in 1..scale {
+            image.extend_from_within(row_start..row_start + out_width);
+        }
+    }
+    image
+}
+
+/// Encode palette indexes as an indexed-color PNG
+pub fn encode_png(indexed: &[u8], width: u32, height: u32) -> Result<Vec<u8>, png::EncodingError> {
+    let mut out = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut out, width, height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(palette_rgb());
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(indexed)?;
+    }
+    Ok(out)
+}
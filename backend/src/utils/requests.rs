@@ -1,52 +1,319 @@
-use axum::http::HeaderMap;
+use axum::http::{HeaderMap, HeaderName, header};
+use ipnet::IpNet;
+use sha2::{Digest, Sha256};
 
-use std::time::{Duration, SystemTime};
+use std::net::{IpAddr, SocketAddr};
 
+use crate::config::ForwardedHeader;
 use crate::routes::state::AppState;
 
-/// Get the IP address from headers or socket address, mimicking PHP's logic
-pub fn get_ip(headers: &HeaderMap) -> String {
-    if let Some(ip) = headers.get("HTTP_CLIENT_IP").and_then(|v| v.to_str().ok()) {
-        return ip.to_string();
+/// Proxies allowed to set the client address, and the header they set it in
+pub struct TrustedProxies {
+    pub networks: Vec<IpNet>,
+    pub header: ForwardedHeader,
+}
+
+/// Client address: the socket peer, unless it is a trusted proxy, in which case the
+/// configured forwarding header is walked right-to-left and the first untrusted hop wins.
+/// Other forwarding headers are ignored, the proxy passes them through from the client.
+pub fn get_ip(headers: &HeaderMap, peer: SocketAddr, proxies: &TrustedProxies) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| proxies.networks.iter().any(|net| net.contains(ip));
+
+    let mut client = normalize(peer.ip());
+    if !is_trusted(&client) {
+        return client;
     }
 
-    if let Some(ip) = headers
-        .get("HTTP_X_FORWARDED_FOR")
-        .and_then(|v| v.to_str().ok())
-    {
-        return ip.to_string();
+    for hop in forwarded_chain(headers, proxies.header).iter().rev() {
+        match hop {
+            // The proxy in front of an unparsable entry can't vouch for anything before it
+            None => break,
+            Some(ip) => {
+                client = *ip;
+                if !is_trusted(ip) {
+                    break;
+                }
+            }
+        }
     }
+    client
+}
 
-    if let Some(ip) = headers.get("REMOTE_ADDR").and_then(|v| v.to_str().ok()) {
-        return ip.to_string();
+/// Addresses from the forwarding header, oldest hop first.
+/// `None` marks an entry that isn't an IP address (`unknown`, obfuscated identifiers...)
+fn forwarded_chain(headers: &HeaderMap, header: ForwardedHeader) -> Vec<Option<IpAddr>> {
+    let name = match header {
+        ForwardedHeader::XForwardedFor => HeaderName::from_static("x-forwarded-for"),
+        ForwardedHeader::Forwarded => header::FORWARDED,
+        ForwardedHeader::XRealIp => HeaderName::from_static("x-real-ip"),
+    };
+    let elements = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim);
+
+    match header {
+        ForwardedHeader::Forwarded => elements
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node.trim().trim_matches('"')))
+            })
+            .collect(),
+        ForwardedHeader::XForwardedFor | ForwardedHeader::XRealIp => {
+            elements.map(parse_node).collect()
+        }
     }
+}
 
-    "unknown".to_string()
+/// Parse `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` or `[2001:db8::1]:80`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let ip = if let Ok(ip) = node.parse::<IpAddr>() {
+        ip
+    } else if let Ok(addr) = node.parse::<SocketAddr>() {
+        addr.ip()
+    } else {
+        node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()?
+    };
+    Some(normalize(ip))
 }
 
-pub async fn is_request_allowed(ip: &str, state: &AppState) -> bool {
-    let mut timestamps = state.ip_timestamps.lock().await;
+/// IPv4 clients reaching a dual-stack socket show up as `::ffff:a.b.c.d`
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
 
-    let now = SystemTime::now();
-    match timestamps.get(ip) {
-        Some(&last_time) => {
-            if let Ok(elapsed) = now.duration_since(last_time) {
-                let delay = *state.delay.lock().await as u64;
-                if elapsed >= Duration::from_secs(delay) {
-                    timestamps.insert(ip.to_string(), now);
-                    true
-                } else {
-                    false
-                }
-            } else {
-                // Clock went backwards
-                timestamps.insert(ip.to_string(), now);
-                true
+fn salted_hash(state: &AppState, kind: &str, value: &str) -> String {
+    let mut hasher = Sha256::new();
+    // The cookie signing key is secret and stable, a good salt against dictionary attacks
+    hasher.update(state.cookie_key.signing());
+    hasher.update(kind.as_bytes());
+    hasher.update(value.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Salted hash of a client IP, stored instead of the IP in the placement log.
+/// Normalized first so every spelling of an address hashes the same.
+pub fn hash_ip(state: &AppState, ip: IpAddr) -> String {
+    salted_hash(state, "ip:", &normalize(ip).to_string())
+}
+
+/// Stable pseudonym for an actor, shown to non-admins
+pub fn anonymize(state: &AppState, actor: &str) -> String {
+    salted_hash(state, "actor:", actor)
+}
+
+/// Whether the client already holds the representation tagged `etag`
+pub fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|tags| {
+            tags.split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        })
+}
+
+/// Outcome of parsing a `Range` header against a body of known length
+pub enum ByteRange {
+    Full,
+    Partial { start: usize, end: usize }, // inclusive
+    Unsatisfiable,
+}
+
+/// Parse a single `bytes=` range; malformed or multi-range headers are ignored
+pub fn parse_range(headers: &HeaderMap, len: usize) -> ByteRange {
+    let Some(spec) = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().strip_prefix("bytes="))
+    else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+
+    let (start, end) = match (first.parse::<usize>(), last.parse::<usize>()) {
+        // bytes=a-b
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        // bytes=a-
+        (Ok(start), Err(_)) if last.is_empty() => (start, len.saturating_sub(1)),
+        // bytes=-n, the last n bytes
+        (Err(_), Ok(suffix)) if first.is_empty() => {
+            if suffix == 0 {
+                return ByteRange::Unsatisfiable;
             }
+            (len.saturating_sub(suffix), len.saturating_sub(1))
         }
-        None => {
-            timestamps.insert(ip.to_string(), now);
-            true
+        _ => return ByteRange::Full,
+    };
+
+    if len == 0 || start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial { start, end }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn proxies(header: ForwardedHeader) -> TrustedProxies {
+        TrustedProxies {
+            networks: vec!["10.0.0.0/8".parse().unwrap()],
+            header,
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.append(name, HeaderValue::from_static(value));
         }
+        headers
+    }
+
+    fn peer(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 40000)
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peers_are_taken_as_is() {
+        let headers = headers(&[("x-forwarded-for", "1.2.3.4")]);
+        let proxies = proxies(ForwardedHeader::XForwardedFor);
+        assert_eq!(
+            get_ip(&headers, peer("192.0.2.1"), &proxies),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            get_ip(&headers, peer("::ffff:192.0.2.1"), &proxies),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_chain_is_walked_through_trusted_hops() {
+        let headers = headers(&[("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2")]);
+        let proxies = proxies(ForwardedHeader::XForwardedFor);
+        // The client may prepend anything, only the first untrusted hop from the right counts
+        assert_eq!(get_ip(&headers, peer("10.0.0.1"), &proxies), ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn only_the_configured_header_is_read() {
+        let headers = headers(&[
+            ("forwarded", "for=6.6.6.6"),
+            ("x-real-ip", "7.7.7.7"),
+            ("x-forwarded-for", "1.2.3.4"),
+        ]);
+        let peer = peer("10.0.0.1");
+        assert_eq!(
+            get_ip(&headers, peer, &proxies(ForwardedHeader::XForwardedFor)),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            get_ip(&headers, peer, &proxies(ForwardedHeader::Forwarded)),
+            ip("6.6.6.6")
+        );
+        assert_eq!(
+            get_ip(&headers, peer, &proxies(ForwardedHeader::XRealIp)),
+            ip("7.7.7.7")
+        );
+
+        // No fallback to another header when the configured one is missing
+        let spoofed = self::headers(&[("forwarded", "for=6.6.6.6")]);
+        assert_eq!(
+            get_ip(&spoofed, peer, &proxies(ForwardedHeader::XForwardedFor)),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_nodes_are_parsed() {
+        let headers = headers(&[("forwarded", r#"for="[2001:db8::1]:4711";proto=https"#)]);
+        assert_eq!(
+            get_ip(
+                &headers,
+                peer("10.0.0.1"),
+                &proxies(ForwardedHeader::Forwarded)
+            ),
+            ip("2001:db8::1")
+        );
+        assert_eq!(parse_node("1.2.3.4:80"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_node("::ffff:1.2.3.4"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn unparsable_hops_stop_the_walk() {
+        let headers = headers(&[("x-forwarded-for", "1.2.3.4, unknown, 10.0.0.2")]);
+        let proxies = proxies(ForwardedHeader::XForwardedFor);
+        assert_eq!(get_ip(&headers, peer("10.0.0.1"), &proxies), ip("10.0.0.2"));
+    }
+
+    fn range(value: &'static str, len: usize) -> ByteRange {
+        parse_range(&headers(&[("range", value)]), len)
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert!(matches!(
+            parse_range(&HeaderMap::new(), 10),
+            ByteRange::Full
+        ));
+        assert!(matches!(
+            range("bytes=2-5", 10),
+            ByteRange::Partial { start: 2, end: 5 }
+        ));
+        assert!(matches!(
+            range("bytes=2-50", 10),
+            ByteRange::Partial { start: 2, end: 9 }
+        ));
+        assert!(matches!(
+            range("bytes=7-", 10),
+            ByteRange::Partial { start: 7, end: 9 }
+        ));
+        assert!(matches!(
+            range("bytes=-3", 10),
+            ByteRange::Partial { start: 7, end: 9 }
+        ));
+        assert!(matches!(
+            range("bytes=-30", 10),
+            ByteRange::Partial { start: 0, end: 9 }
+        ));
+    }
+
+    #[test]
+    fn unsatisfiable_and_ignored_ranges() {
+        assert!(matches!(range("bytes=10-", 10), ByteRange::Unsatisfiable));
+        assert!(matches!(range("bytes=-0", 10), ByteRange::Unsatisfiable));
+        assert!(matches!(range("bytes=0-", 0), ByteRange::Unsatisfiable));
+        assert!(matches!(range("bytes=5-2", 10), ByteRange::Full));
+        assert!(matches!(range("bytes=0-1,4-5", 10), ByteRange::Full));
+        assert!(matches!(range("items=0-1", 10), ByteRange::Full));
+        assert!(matches!(range("bytes=a-b", 10), ByteRange::Full));
     }
 }
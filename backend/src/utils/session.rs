@@ -0,0 +1,68 @@
+use axum::{
+    extract::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::cookie::{Cookie, PrivateCookieJar, SameSite};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+pub const SESSION_COOKIE: &str = "session";
+pub const USER_COOKIE: &str = "user";
+
+/// Random id of an anonymous visitor, carried in an encrypted cookie
+#[derive(Clone)]
+pub struct AnonymousSession(pub String);
+
+/// Player logged in through OpenID Connect, stored as JSON in a private cookie
+#[derive(Serialize, Deserialize, Clone)]
+pub struct User {
+    pub id: String, // `sub` claim of the provider
+    pub name: String,
+}
+
+pub fn current_user(jar: &PrivateCookieJar) -> Option<User> {
+    jar.get(USER_COOKIE)
+        .and_then(|cookie| serde_json::from_str(cookie.value()).ok())
+}
+
+/// Key of cooldowns and placement log entries: logged in players are tracked across devices
+pub fn actor_id(user: Option<&User>, session: &AnonymousSession) -> String {
+    match user {
+        Some(user) => format!("user:{}", user.id),
+        None => format!("session:{}", session.0),
+    }
+}
+
+/// 128 random bits, hex encoded
+pub fn random_token() -> String {
+    rand::rng()
+        .random::<[u8; 16]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Middleware giving every visitor an anonymous session, issued on the first request.
+/// Handlers read it with `Extension<AnonymousSession>`.
+pub async fn ensure_session(jar: PrivateCookieJar, mut request: Request, next: Next) -> Response {
+    if let Some(cookie) = jar.get(SESSION_COOKIE) {
+        request
+            .extensions_mut()
+            .insert(AnonymousSession(cookie.value().to_string()));
+        return next.run(request).await;
+    }
+
+    let id = random_token();
+    request
+        .extensions_mut()
+        .insert(AnonymousSession(id.clone()));
+    let response = next.run(request).await;
+
+    let mut cookie = Cookie::new(SESSION_COOKIE, id);
+    cookie.set_http_only(true);
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_max_age(time::Duration::days(365));
+    cookie.set_path("/");
+    (jar.add(cookie), response).into_response()
+}
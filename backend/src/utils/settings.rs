@@ -0,0 +1,70 @@
+use crate::config::AppConfig;
+use crate::routes::state::AppState;
+use crate::utils::canvas::write_atomically;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Values changed through the admin API, persisted in `settings.json` next to the canvas.
+/// They overlay `config.toml` at startup. The canvas size lives in the `pixels.bin` header.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct RuntimeSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket_size: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pow_difficulty: Option<u8>,
+}
+
+pub fn settings_path(file_path: &str) -> String {
+    Path::new(file_path)
+        .with_file_name("settings.json")
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl RuntimeSettings {
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(RuntimeSettings::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Override the config values with the persisted ones
+    pub fn apply(&self, config: &mut AppConfig) {
+        if let Some(delay) = self.delay {
+            config.file.delay = delay;
+        }
+        if let Some(active) = self.active {
+            config.state.active = active;
+        }
+        if let Some(bucket_size) = self.bucket_size {
+            config.limits.bucket_size = bucket_size;
+        }
+        if let Some(pow_difficulty) = self.pow_difficulty {
+            config.limits.pow_difficulty = pow_difficulty;
+        }
+    }
+}
+
+/// Apply `change` to the persisted settings and atomically rewrite the file
+pub async fn update_settings(
+    state: &AppState,
+    change: impl FnOnce(&mut RuntimeSettings),
+) -> std::io::Result<()> {
+    let mut settings = state.settings.lock().await;
+    let mut updated = settings.clone();
+    change(&mut updated);
+
+    let data = serde_json::to_vec_pretty(&updated).map_err(std::io::Error::other)?;
+    write_atomically(&settings_path(&state.file_path), &data)?;
+
+    *settings = updated;
+    Ok(())
+}
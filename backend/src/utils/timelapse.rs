@@ -0,0 +1,182 @@
+use crate::config::AppConfig;
+use crate::routes::state::CanvasSize;
+use crate::utils::canvas::set_nibble;
+use crate::utils::history::{Placement, PlacementLog, history_path};
+use crate::utils::pixel_file::{self, PixelFile};
+use crate::utils::render::{MAX_SCALE, Region, palette_rgb, render_indexed};
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+pub const MAX_FRAMES: usize = 5000;
+
+pub struct TimelapseOptions {
+    pub interval_ms: u64, // placement time covered by one frame
+    pub frame_delay: u16, // display time of a frame, in hundredths of a second
+    pub scale: u32,
+    pub region: Option<Region>, // whole canvas when `None`
+}
+
+/// Replay the placement log over a blank canvas into an animated GIF.
+/// Intervals without any placement don't produce a frame.
+pub fn render_timelapse(
+    placements: &[Placement],
+    size: &CanvasSize,
+    options: &TimelapseOptions,
+) -> std::io::Result<Vec<u8>> {
+    let region = options.region.unwrap_or_else(|| Region::full(size));
+    if !region.fits(size) {
+        return Err(Error::new(ErrorKind::InvalidInput, "Region out of bounds"));
+    }
+    if options.interval_ms == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Interval must be positive",
+        ));
+    }
+    if options.scale == 0 || options.scale > MAX_SCALE {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Scale must be between 1 and {}", MAX_SCALE),
+        ));
+    }
+
+    // Every frame is rendered in full before being encoded
+    let Some((out_width, out_height)) = region.output_size(options.scale) else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Timelapse frames are too large",
+        ));
+    };
+    if out_width > u16::MAX as u32 || out_height > u16::MAX as u32 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Timelapse frames are too large for a GIF",
+        ));
+    }
+
+    let mut data = vec![0u8; (size.width * size.height / 2) as usize];
+    let mut out = Vec::new();
+    {
+        let palette = palette_rgb();
+        let mut encoder =
+            gif::Encoder::new(&mut out, out_width as u16, out_height as u16, &palette)
+                .map_err(Error::other)?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(Error::other)?;
+
+        let mut frames = 0;
+        let mut write_frame = |data: &[u8]| -> std::io::Result<()> {
+            frames += 1;
+            if frames > MAX_FRAMES {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("More than {} frames, use a larger interval", MAX_FRAMES),
+                ));
+            }
+            let pixels = render_indexed(data, size.width, &region, options.scale);
+            let mut frame =
+                gif::Frame::from_indexed_pixels(out_width as u16, out_height as u16, pixels, None);
+            frame.delay = options.frame_delay;
+            encoder.write_frame(&frame).map_err(Error::other)
+        };
+
+        // Blank first frame, then one frame per interval that saw placements
+        write_frame(&data)?;
+        let mut frame_end = placements
+            .first()
+            .map_or(0, |p| p.timestamp + options.interval_ms);
+        let mut pending = false;
+
+        for placement in placements {
+            if placement.timestamp >= frame_end {
+                if pending {
+                    write_frame(&data)?;
+                    pending = false;
+                }
+                // Jump straight to the interval containing this placement
+                let skipped = (placement.timestamp - frame_end) / options.interval_ms;
+                frame_end += (skipped + 1) * options.interval_ms;
+            }
+
+            if placement.x < size.width && placement.y < size.height {
+                set_nibble(
+                    &mut data,
+                    placement.y * size.width + placement.x,
+                    placement.color,
+                );
+                pending = true;
+            }
+        }
+        if pending {
+            write_frame(&data)?;
+        }
+    }
+    Ok(out)
+}
+
+const USAGE: &str = "Usage: backend timelapse <output.gif> [--interval SECONDS] [--frame-delay CENTISECONDS] [--scale N] [--region X_START,Y_START,X_END,Y_END]";
+
+/// `backend timelapse ...`: render the placement log of `config` to a GIF file
+pub fn run_command(config: &AppConfig, args: &[String]) -> Result<(), String> {
+    let mut args = args.iter();
+    let output = args.next().ok_or(USAGE)?;
+
+    let mut options = TimelapseOptions {
+        interval_ms: 60_000,
+        frame_delay: 10,
+        scale: 1,
+        region: None,
+    };
+
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or(USAGE)?;
+        let parse_error = |_| format!("Invalid value for {}: {}", flag, value);
+        match flag.as_str() {
+            "--interval" => {
+                options.interval_ms = value
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|seconds| seconds.checked_mul(1000))
+                    .ok_or_else(|| format!("Invalid value for {}: {}", flag, value))?
+            }
+            "--frame-delay" => options.frame_delay = value.parse().map_err(parse_error)?,
+            "--scale" => options.scale = value.parse().map_err(parse_error)?,
+            "--region" => {
+                let bounds = value
+                    .split(',')
+                    .map(|v| v.trim().parse::<u32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(parse_error)?;
+                let [x_start, y_start, x_end, y_end] = bounds[..] else {
+                    return Err(format!("--region needs four values, got {}", value));
+                };
+                options.region = Some(Region {
+                    x_start,
+                    y_start,
+                    x_end,
+                    y_end,
+                });
+            }
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let file_path = &config.file.file_path;
+    let size = match fs::read(file_path).and_then(pixel_file::decode) {
+        Ok(PixelFile::Versioned { size, .. }) => size,
+        Ok(PixelFile::Headerless { .. }) => CanvasSize {
+            width: config.file.width,
+            height: config.file.height,
+        },
+        Err(e) => return Err(format!("Failed to read {}: {}", file_path, e)),
+    };
+
+    let placements = PlacementLog::read(&history_path(file_path))
+        .map_err(|e| format!("Failed to read the placement log: {}", e))?;
+    let gif = render_timelapse(&placements, &size, &options).map_err(|e| e.to_string())?;
+    fs::write(output, gif).map_err(|e| format!("Failed to write {}: {}", output, e))?;
+
+    println!("Rendered {} placements to {}", placements.len(), output);
+    Ok(())
+}
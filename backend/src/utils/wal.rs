@@ -0,0 +1,200 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+
+/// Size of an encoded record: opcode + four little-endian u32
+const RECORD_SIZE: usize = 17;
+
+const OP_PIXEL: u8 = 1;
+const OP_WHITEN: u8 = 2;
+
+/// Canvas operation recorded before it is applied in memory
+#[derive(Debug, Clone, Copy)]
+pub enum WalOp {
+    Pixel {
+        x: u32,
+        y: u32,
+        color_index: u8,
+    },
+    Whiten {
+        x_min: u32,
+        y_min: u32,
+        x_max: u32,
+        y_max: u32,
+    },
+}
+
+impl WalOp {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let (op, fields) = match *self {
+            WalOp::Pixel { x, y, color_index } => (OP_PIXEL, [x, y, color_index as u32, 0]),
+            WalOp::Whiten {
+                x_min,
+                y_min,
+                x_max,
+                y_max,
+            } => (OP_WHITEN, [x_min, y_min, x_max, y_max]),
+        };
+
+        let mut record = [0u8; RECORD_SIZE];
+        record[0] = op;
+        for (i, field) in fields.iter().enumerate() {
+            record[1 + i * 4..5 + i * 4].copy_from_slice(&field.to_le_bytes());
+        }
+        record
+    }
+
+    fn decode(record: &[u8]) -> Option<Self> {
+        let field = |i: usize| u32::from_le_bytes(record[1 + i * 4..5 + i * 4].try_into().unwrap());
+        match record[0] {
+            OP_PIXEL => Some(WalOp::Pixel {
+                x: field(0),
+                y: field(1),
+                color_index: field(2) as u8,
+            }),
+            OP_WHITEN => Some(WalOp::Whiten {
+                x_min: field(0),
+                y_min: field(1),
+                x_max: field(2),
+                y_max: field(3),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Append-only log of the operations applied since the last snapshot
+pub struct Wal {
+    file: File,
+}
+
+impl Wal {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Wal { file })
+    }
+
+    /// Read back every complete record, a torn trailing record is ignored
+    pub fn read_all(path: &str) -> std::io::Result<Vec<WalOp>> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        if !data.len().is_multiple_of(RECORD_SIZE) {
+            tracing::warn!("{} ends with a partial record, ignoring it", path);
+        }
+
+        let mut ops = Vec::with_capacity(data.len() / RECORD_SIZE);
+        for record in data.chunks_exact(RECORD_SIZE) {
+            match WalOp::decode(record) {
+                Some(op) => ops.push(op),
+                None => {
+                    tracing::warn!("{} contains an unknown record, stopping replay", path);
+                    break;
+                }
+            }
+        }
+        Ok(ops)
+    }
+
+    /// Write the records and wait for them to reach the disk, so an acknowledged
+    /// change survives a power loss and not just a crash of the process
+    pub fn append(&mut self, ops: &[WalOp]) -> std::io::Result<()> {
+        let records: Vec<u8> = ops.iter().flat_map(|op| op.encode()).collect();
+        self.file.write_all(&records)?;
+        self.file.sync_data()
+    }
+
+    /// Forget every record, once they are covered by a snapshot
+    pub fn truncate(&mut self) -> std::io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let pixel = WalOp::Pixel {
+            x: 7,
+            y: 70_000,
+            color_index: 15,
+        };
+        assert!(matches!(
+            WalOp::decode(&pixel.encode()),
+            Some(WalOp::Pixel {
+                x: 7,
+                y: 70_000,
+                color_index: 15
+            })
+        ));
+
+        let whiten = WalOp::Whiten {
+            x_min: 1,
+            y_min: 2,
+            x_max: 3,
+            y_max: u32::MAX,
+        };
+        assert!(matches!(
+            WalOp::decode(&whiten.encode()),
+            Some(WalOp::Whiten {
+                x_min: 1,
+                y_min: 2,
+                x_max: 3,
+                y_max: u32::MAX
+            })
+        ));
+    }
+
+    #[test]
+    fn unknown_opcode_is_rejected() {
+        let mut record = WalOp::Pixel {
+            x: 0,
+            y: 0,
+            color_index: 0,
+        }
+        .encode();
+        record[0] = 0xff;
+        assert!(WalOp::decode(&record).is_none());
+    }
+
+    #[test]
+    fn torn_trailing_record_is_ignored() {
+        let path = temp_path("wal-torn");
+        let _ = fs::remove_file(&path);
+        let mut wal = Wal::open(&path).unwrap();
+        let ops: Vec<WalOp> = (0..3)
+            .map(|x| WalOp::Pixel {
+                x,
+                y: 0,
+                color_index: 1,
+            })
+            .collect();
+        wal.append(&ops[..1]).unwrap();
+        wal.append(&ops[1..]).unwrap();
+        // A crash in the middle of a write
+        wal.file.write_all(&[OP_PIXEL, 1, 2]).unwrap();
+
+        let ops = Wal::read_all(&path).unwrap();
+        assert_eq!(ops.len(), 3);
+        assert!(matches!(ops[2], WalOp::Pixel { x: 2, .. }));
+
+        wal.truncate().unwrap();
+        assert!(Wal::read_all(&path).unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_file_is_empty() {
+        assert!(Wal::read_all(&temp_path("wal-missing")).unwrap().is_empty());
+    }
+}